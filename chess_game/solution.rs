@@ -1,22 +1,33 @@
-
+// The demo in main uses only a part of the library, the rest is covered by the tests
+#![cfg_attr(not(test), allow(dead_code))]
 
 use core::convert::TryFrom;
 use core::convert::TryInto;
 use tokio::sync::{Mutex, mpsc};
 use std::sync::Arc; 
+use std::time::{Duration, Instant};
 use std::error::Error as StdError;
 use std::fmt;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
 
-
-use Color::*;
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Color {
     White,
     Black,
 }   
 
+impl Color {
+    pub fn opposite(self) -> Color {
+        match self {
+            Color::White => Color::Black,
+            Color::Black => Color::White,
+        }
+    }
+}
+
 use PieceType::*;
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub enum PieceType {
     King,
     Queen,
@@ -26,35 +37,122 @@ pub enum PieceType {
     Pawn,
 }
 
+impl PieceType {
+    // Conventional value in pawns, the king is priceless and counts as nothing
+    fn material_value(&self) -> i32 {
+        match self {
+            King => 0,
+            Queen => 9,
+            Rook => 5,
+            Bishop | Knight => 3,
+            Pawn => 1,
+        }
+    }
+}
+
 use Piece::{Black, White};
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Serialize, Deserialize)]
 pub enum Piece {
     White(PieceType),
     Black(PieceType),
 }
 
 impl Piece {
+    fn new(color: Color, piece_type: PieceType) -> Self {
+        match color {
+            Color::White => White(piece_type),
+            Color::Black => Black(piece_type),
+        }
+    }
+
     fn get_color(&self) -> Color {
         match self {
             White(_) => Color::White,
             Black(_) => Color::Black,
         }
     }
+
+    fn get_type(&self) -> PieceType {
+        match self {
+            White(piece_type) | Black(piece_type) => *piece_type,
+        }
+    }
+
+    /// Letter of the piece for terminals without unicode, uppercase for white and lowercase for black.
+    pub fn to_ascii(self) -> char {
+        let symbol = match self.get_type() {
+            King => 'k',
+            Queen => 'q',
+            Rook => 'r',
+            Bishop => 'b',
+            Knight => 'n',
+            Pawn => 'p',
+        };
+        match self {
+            White(_) => symbol.to_ascii_uppercase(),
+            Black(_) => symbol,
+        }
+    }
+}
+
+impl fmt::Display for Piece {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let glyph = match self {
+            White(King) => '♔',
+            White(Queen) => '♕',
+            White(Rook) => '♖',
+            White(Bishop) => '♗',
+            White(Knight) => '♘',
+            White(Pawn) => '♙',
+            Black(King) => '♚',
+            Black(Queen) => '♛',
+            Black(Rook) => '♜',
+            Black(Bishop) => '♝',
+            Black(Knight) => '♞',
+            Black(Pawn) => '♟',
+        };
+        write!(f, "{}", glyph)
+    }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 pub struct Position {
     row: usize,    // 0-7 for rows 1-8 on the chessboard
     column: usize, // 0-7 for columns a-h on the chessboard
 }
 
+/// A single move in the `e2-e4` notation, optionally with a promotion like `e7-e8=Q`.
+#[derive(Copy, Clone, PartialEq)]
+pub struct Move {
+    from: Position,
+    to: Position,
+    promotion: Option<PieceType>,
+}
+
 use Turn::*;
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Serialize, Deserialize)]
 pub enum Turn {
     WhitePlays,
     BlackPlays
 }
 
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum GameResult {
+    WhiteWins,
+    BlackWins,
+    Draw,
+}
+
+impl fmt::Display for GameResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GameResult::WhiteWins => write!(f, "White wins"),
+            GameResult::BlackWins => write!(f, "Black wins"),
+            GameResult::Draw => write!(f, "Draw"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     OpponentGone(String),
@@ -74,16 +172,22 @@ impl fmt::Display for Error {
 
 impl StdError for Error {}
 
-struct ChessBoard {
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ChessBoard {
     state: [[Option<Piece>; 8]; 8]
 }
 
 impl ChessBoard {
+    fn empty() -> Self {
+        ChessBoard { state: Default::default() }
+    }
+
     fn new() -> Self {
         // Initialize an empty board
         let mut state: [[Option<Piece>; 8]; 8] = Default::default();
 
-        // Place black pieces
+        // Row 0 is rank 1, so white takes ranks 1 and 2 and black ranks 7 and 8
+        // Place white pieces
         state[0] = [
             Some(White(Rook)),
             Some(White(Knight)),
@@ -94,12 +198,10 @@ impl ChessBoard {
             Some(White(Knight)),
             Some(White(Rook)),
         ];
-        for i in 0..8 {
-            state[1][i] = Some(White(Pawn));
-            state[6][i] = Some(Black(Pawn));
-        }
+        state[1] = [Some(White(Pawn)); 8];
+        state[6] = [Some(Black(Pawn)); 8];
 
-        // Place white pieces
+        // Place black pieces
         state[7] = [
             Some(Black(Rook)),
             Some(Black(Knight)),
@@ -126,12 +228,104 @@ impl ChessBoard {
     fn set_field(&mut self, position: Position, piece: Option<Piece>) {
         self.state[position.row][position.column] = piece;
     }
+
+    /// Yields every occupied square with its piece, row by row from rank 1 and from column a within a row.
+    pub fn pieces(&self) -> impl Iterator<Item = (Position, Piece)> + '_ {
+        self.state.iter().enumerate().flat_map(|(row, fields)| {
+            fields.iter().enumerate().filter_map(move |(column, field)| {
+                field.map(|piece| (Position { row, column }, piece))
+            })
+        })
+    }
+
+    /// Returns a copy flipped upside down, row `r` becomes row `7 - r` and the pieces keep their colors.
+    pub fn mirrored(&self) -> ChessBoard {
+        let mut state = self.state;
+        state.reverse();
+        ChessBoard { state }
+    }
+
+    /// Returns where the king of the given color stands, `None` on a board without one.
+    pub fn king_position(&self, color: Color) -> Option<Position> {
+        self.pieces()
+            .find(|(_, piece)| piece.get_color() == color && piece.get_type() == King)
+            .map(|(position, _)| position)
+    }
+
+    /// Renders the board as seen by the given player, with their pieces at the bottom.
+    pub fn render(&self, perspective: Color) -> String {
+        let rows: Vec<usize> = match perspective {
+            Color::White => (0..8).rev().collect(),
+            Color::Black => (0..8).collect(),
+        };
+        let columns: Vec<usize> = match perspective {
+            Color::White => (0..8).collect(),
+            Color::Black => (0..8).rev().collect(),
+        };
+
+        let mut output = String::new();
+        for &row in &rows {
+            output.push((b'1' + row as u8) as char);
+            for &column in &columns {
+                output.push(' ');
+                output.push(self.state[row][column].map_or('.', Piece::to_ascii));
+            }
+            output.push('\n');
+        }
+        output.push(' ');
+        for &column in &columns {
+            output.push(' ');
+            output.push((b'a' + column as u8) as char);
+        }
+        output
+    }
+}
+
+impl fmt::Display for ChessBoard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.render(Color::White))
+    }
 }
 
 impl Position {
     pub fn is_valid(&self) -> bool {
         self.row < 8 && self.column < 8
     }
+
+    // Returns the position shifted by the given number of rows and columns if it stays on the board
+    fn offset(&self, row_delta: i32, column_delta: i32) -> Option<Position> {
+        let row = self.row as i32 + row_delta;
+        let column = self.column as i32 + column_delta;
+        if (0..8).contains(&row) && (0..8).contains(&column) {
+            Some(Position { row: row as usize, column: column as usize })
+        } else {
+            None
+        }
+    }
+}
+
+const KNIGHT_OFFSETS: [(i32, i32); 8] = [(1, 2), (2, 1), (2, -1), (1, -2), (-1, -2), (-2, -1), (-2, 1), (-1, 2)];
+const KING_OFFSETS: [(i32, i32); 8] = [(1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0), (-1, -1), (0, -1), (1, -1)];
+const ROOK_DIRECTIONS: [(i32, i32); 4] = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+const BISHOP_DIRECTIONS: [(i32, i32); 4] = [(1, 1), (-1, 1), (-1, -1), (1, -1)];
+const PROMOTION_TYPES: [PieceType; 4] = [Queen, Rook, Bishop, Knight];
+
+// Rows are counted from white's side, so white pawns move up and black pawns move down
+fn pawn_direction(color: Color) -> i32 {
+    match color {
+        Color::White => 1,
+        Color::Black => -1,
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Positions only come from parsing or the board itself, an off-board one is a bug
+        assert!(self.is_valid(), "Cannot format an off-board position");
+        let column = (b'a' + self.column as u8) as char;
+        let row = (b'1' + self.row as u8) as char;
+        write!(f, "{}{}", column, row)
+    }
 }
 
 impl TryFrom<&str> for Position {
@@ -144,7 +338,7 @@ impl TryFrom<&str> for Position {
         let col = value.chars().nth(0).unwrap();
         let row = value.chars().nth(1).unwrap();
 
-        if col >= 'a' && col <= 'h' && row >= '1' && row <= '8' {
+        if ('a'..='h').contains(&col) && ('1'..='8').contains(&row) {
             let column = col as usize - 'a' as usize; // Convert letter to 0-7
             let row = row.to_digit(10).unwrap() as usize - 1; // Convert number to 0-7
             Ok(Position { row, column })
@@ -154,6 +348,51 @@ impl TryFrom<&str> for Position {
     }
 }
 
+impl Move {
+    fn new(from: Position, to: Position) -> Self {
+        Move { from, to, promotion: None }
+    }
+}
+
+impl fmt::Display for Move {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.from, self.to)?;
+        if let Some(piece_type) = self.promotion {
+            write!(f, "={}", White(piece_type).to_ascii())?;
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<&str> for Move {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let (squares, promotion) = match value.split_once('=') {
+            Some((squares, piece)) => {
+                let piece_type = match piece {
+                    "Q" => Queen,
+                    "R" => Rook,
+                    "B" => Bishop,
+                    "N" => Knight,
+                    _ => return Err(Error::Other("Invalid promotion piece".to_string())),
+                };
+                (squares, Some(piece_type))
+            },
+            None => (value, None),
+        };
+
+        let parts: Vec<&str> = squares.split('-').collect();
+        if parts.len() != 2 {
+            return Err(Error::Other("Invalid move format".to_string()));
+        }
+
+        let from = parts[0].try_into().map_err(|_| Error::Other("Invalid start position".to_string()))?;
+        let to = parts[1].try_into().map_err(|_| Error::Other("Invalid end position".to_string()))?;
+        Ok(Move { from, to, promotion })
+    }
+}
+
 impl Turn {
     fn get_color(&self) -> Color {
         match self {
@@ -163,9 +402,9 @@ impl Turn {
     }
 
     fn change(&mut self) {
-        *self = match self {
-            WhitePlays => BlackPlays,
-            BlackPlays => WhitePlays,
+        *self = match self.get_color().opposite() {
+            Color::White => WhitePlays,
+            Color::Black => BlackPlays,
         }
     }
 }
@@ -181,14 +420,65 @@ pub struct Game {
     black_update_receiver: Option<mpsc::Receiver<String>>,
     game_state: Arc<Mutex<GameState>>,
     player_created: u8, 
+    spectators: Vec<mpsc::Sender<String>>,
+    move_timeout: Option<Duration>, // how long the side to move may think before losing, None waits forever
 }
 
-struct GameState {
+const TIME_CONTROL: Duration = Duration::from_secs(15 * 60);
+
+// Besides moves, either player may send one of these at any time
+const GAME_MESSAGES: [&str; 3] = ["resign", "offer-draw", "accept-draw"];
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GameState {
     pub board: ChessBoard, 
     current_turn: Turn,
+    time_remaining: [Duration; 2], // indexed by Color
+    #[serde(skip)]
+    clock_started: Option<Instant>, // when the side to move started thinking, None while paused
+    draw_offer: Option<Color>, // who offered a draw the opponent has not answered yet
+    agreed_result: Option<GameResult>, // a resignation, forfeit or an accepted draw ends the game regardless of the board
 }
 
 impl GameState {
+    fn new(board: ChessBoard) -> Self {
+        GameState {
+            board,
+            current_turn: WhitePlays,
+            time_remaining: [TIME_CONTROL; 2],
+            clock_started: None,
+            draw_offer: None,
+            agreed_result: None,
+        }
+    }
+
+    /// Serializes the board, the side to move and the remaining times, the clock has to be stopped first
+    /// as a running clock is not part of the state.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("the game state always serializes")
+    }
+
+    pub fn from_json(json: &str) -> Result<GameState, Error> {
+        serde_json::from_str(json).map_err(|e| Error::Other(format!("Invalid game state: {}", e)))
+    }
+
+    pub fn time_left(&self, color: Color) -> Duration {
+        self.time_remaining[color as usize]
+    }
+
+    // Starts counting the thinking time of the side to move
+    fn start_clock(&mut self, now: Instant) {
+        self.clock_started = Some(now);
+    }
+
+    // Charges the time spent since the clock was started to the side to move and pauses the clock
+    fn stop_clock(&mut self, now: Instant) {
+        if let Some(started) = self.clock_started.take() {
+            let color = self.current_player().get_color() as usize;
+            self.time_remaining[color] = self.time_remaining[color].saturating_sub(now.saturating_duration_since(started));
+        }
+    }
+
     pub fn get_field(&self, position: Position) -> Option<Piece> {  
         self.board.get_field(position)
     }
@@ -201,10 +491,63 @@ impl GameState {
         self.set_field(position_from, None);
         self.current_turn.change();
     }
-    pub async fn make_move (&mut self, position_from: Position, position_to: Position) -> Result<Option<Piece>, Error> {
+
+    // Plays the move without any validation and returns the captured piece
+    fn apply_move(&mut self, chess_move: Move) -> Option<Piece> {
+        let captured = self.get_field(chess_move.to);
+        let color = self.current_player().get_color();
+        self.move_piece(chess_move.from, chess_move.to);
+        if let Some(piece_type) = chess_move.promotion {
+            self.set_field(chess_move.to, Some(Piece::new(color, piece_type)));
+        }
+        captured
+    }
+
+    pub async fn make_move(&mut self, chess_move: Move) -> Result<Option<Piece>, Error> {
+        self.validate_move(chess_move)?;
+        // Playing on instead of accepting declines the draw
+        let color = self.current_player().get_color();
+        if self.draw_offer == Some(color.opposite()) {
+            self.draw_offer = None;
+        }
+        Ok(self.apply_move(chess_move))
+    }
+
+    /// Ends the game in favor of the opponent of the given color.
+    pub fn resign(&mut self, color: Color) {
+        self.agreed_result = Some(match color {
+            Color::White => GameResult::BlackWins,
+            Color::Black => GameResult::WhiteWins,
+        });
+    }
+
+    pub fn offer_draw(&mut self, color: Color) {
+        self.draw_offer = Some(color);
+    }
+
+    /// Ends the game in a draw if the opponent of the given color has offered one.
+    pub fn accept_draw(&mut self, color: Color) -> Result<(), Error> {
+        if self.draw_offer != Some(color.opposite()) {
+            return Err(Error::BadMove("There is no draw offer to accept".to_string()));
+        }
+        self.agreed_result = Some(GameResult::Draw);
+        Ok(())
+    }
+
+    /// Returns true if the side to move may play the move, the board stays untouched.
+    pub fn is_legal(&self, chess_move: Move) -> bool {
+        self.validate_move(chess_move).is_ok()
+    }
+
+    // Describes why the move cannot be played in the current position
+    fn validate_move(&self, chess_move: Move) -> Result<(), Error> {
+        let Move { from: position_from, to: position_to, promotion } = chess_move;
         if !position_from.is_valid() || !position_to.is_valid() {
             return Err(Error::BadMove("Invalid position".to_string()));
         }
+        if position_from == position_to {
+            return Err(Error::BadMove("Source and destination are identical".to_string()));
+        }
         let field_from = self.get_field(position_from);
         let field_to = self.get_field(position_to);
         let piece_from = match field_from {
@@ -213,26 +556,286 @@ impl GameState {
         };
         
         let piece_from_color = piece_from.get_color();
-        if piece_from_color != self.current_turn.get_color() {
+        if piece_from_color != self.current_player().get_color() {
             return Err(Error::BadMove("Not your turn".to_string()));
         }
-        let piece_to = match field_to {
-            Some(piece) => piece,
-            None => {
-                self.move_piece(position_from, position_to);
-                return Ok(None);
+        if let Some(piece_to) = field_to {
+            if piece_from_color == piece_to.get_color() {
+                return Err(Error::BadMove("Cannot take your own piece".to_string()));
+            }
+        }
+        if promotion.is_some() {
+            let last_row = match piece_from_color {
+                Color::White => 7,
+                Color::Black => 0,
+            };
+            if piece_from.get_type() != Pawn || position_to.row != last_row {
+                return Err(Error::BadMove("Only a pawn reaching the last rank can be promoted".to_string()));
+            }
+        }
+        if !self.legal_moves().contains(&chess_move) {
+            return Err(Error::BadMove("Illegal move".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Lists every move the side to move can play without leaving its own king in check.
+    pub fn legal_moves(&self) -> Vec<Move> {
+        let color = self.current_player().get_color();
+        self.pseudo_legal_moves(color)
+            .into_iter()
+            .filter(|&chess_move| {
+                let mut next_state = self.clone();
+                next_state.apply_move(chess_move);
+                !next_state.is_in_check(color)
+            })
+            .collect()
+    }
+
+    // Moves allowed by the movement rules of the pieces, ignoring the safety of the own king
+    fn pseudo_legal_moves(&self, color: Color) -> Vec<Move> {
+        let mut moves = Vec::new();
+        for row in 0..8 {
+            for column in 0..8 {
+                let from = Position { row, column };
+                match self.get_field(from) {
+                    Some(piece) if piece.get_color() == color => self.piece_moves(from, piece, &mut moves),
+                    _ => {},
+                }
+            }
+        }
+        moves
+    }
+
+    fn piece_moves(&self, from: Position, piece: Piece, moves: &mut Vec<Move>) {
+        let color = piece.get_color();
+        match piece.get_type() {
+            Pawn => self.pawn_moves(from, color, moves),
+            Knight => self.step_moves(from, color, &KNIGHT_OFFSETS, moves),
+            King => self.step_moves(from, color, &KING_OFFSETS, moves),
+            Bishop => self.sliding_moves(from, color, &BISHOP_DIRECTIONS, moves),
+            Rook => self.sliding_moves(from, color, &ROOK_DIRECTIONS, moves),
+            Queen => {
+                self.sliding_moves(from, color, &ROOK_DIRECTIONS, moves);
+                self.sliding_moves(from, color, &BISHOP_DIRECTIONS, moves);
+            },
+        }
+    }
+
+    fn pawn_moves(&self, from: Position, color: Color, moves: &mut Vec<Move>) {
+        let direction = pawn_direction(color);
+        let (start_row, last_row) = match color {
+            Color::White => (1, 7),
+            Color::Black => (6, 0),
+        };
+        let mut targets = Vec::new();
+
+        if let Some(one_step) = from.offset(direction, 0).filter(|&to| self.get_field(to).is_none()) {
+            targets.push(one_step);
+            if from.row == start_row {
+                if let Some(two_steps) = one_step.offset(direction, 0).filter(|&to| self.get_field(to).is_none()) {
+                    targets.push(two_steps);
+                }
+            }
+        }
+        for column_delta in [-1, 1] {
+            if let Some(to) = from.offset(direction, column_delta) {
+                if self.get_field(to).is_some_and(|target| target.get_color() != color) {
+                    targets.push(to);
+                }
+            }
+        }
+
+        for to in targets {
+            if to.row == last_row {
+                for piece_type in PROMOTION_TYPES {
+                    moves.push(Move { from, to, promotion: Some(piece_type) });
+                }
+            } else {
+                moves.push(Move::new(from, to));
+            }
+        }
+    }
+
+    fn step_moves(&self, from: Position, color: Color, offsets: &[(i32, i32)], moves: &mut Vec<Move>) {
+        for &(row_delta, column_delta) in offsets {
+            if let Some(to) = from.offset(row_delta, column_delta) {
+                if self.get_field(to).is_none_or(|target| target.get_color() != color) {
+                    moves.push(Move::new(from, to));
+                }
+            }
+        }
+    }
+
+    fn sliding_moves(&self, from: Position, color: Color, directions: &[(i32, i32)], moves: &mut Vec<Move>) {
+        for &(row_delta, column_delta) in directions {
+            let mut current = from;
+            while let Some(to) = current.offset(row_delta, column_delta) {
+                match self.get_field(to) {
+                    None => moves.push(Move::new(from, to)),
+                    Some(target) => {
+                        if target.get_color() != color {
+                            moves.push(Move::new(from, to));
+                        }
+                        break;
+                    },
+                }
+                current = to;
+            }
+        }
+    }
+
+    // Returns true if any piece of the attacker's color could capture on the given position
+    fn is_attacked(&self, position: Position, attacker: Color) -> bool {
+        let is_attacker = |target: Position, piece_types: &[PieceType]| {
+            self.get_field(target).is_some_and(|piece| {
+                piece.get_color() == attacker && piece_types.contains(&piece.get_type())
+            })
+        };
+
+        let pawn_row_delta = -pawn_direction(attacker);
+        if [-1, 1].iter().any(|&column_delta| {
+            position.offset(pawn_row_delta, column_delta).is_some_and(|target| is_attacker(target, &[Pawn]))
+        }) {
+            return true;
+        }
+
+        let step_attackers = [(&KNIGHT_OFFSETS, Knight), (&KING_OFFSETS, King)];
+        for (offsets, piece_type) in step_attackers {
+            if offsets.iter().any(|&(row_delta, column_delta)| {
+                position.offset(row_delta, column_delta).is_some_and(|target| is_attacker(target, &[piece_type]))
+            }) {
+                return true;
+            }
+        }
+
+        let sliding_attackers = [(&ROOK_DIRECTIONS, Rook), (&BISHOP_DIRECTIONS, Bishop)];
+        for (directions, piece_type) in sliding_attackers {
+            for &(row_delta, column_delta) in directions {
+                let mut current = position;
+                while let Some(target) = current.offset(row_delta, column_delta) {
+                    if self.get_field(target).is_some() {
+                        if is_attacker(target, &[piece_type, Queen]) {
+                            return true;
+                        }
+                        break;
+                    }
+                    current = target;
+                }
             }
+        }
+        false
+    }
+
+    /// Lists the squares of the pieces of the given color that shield their king from a rook, bishop or queen.
+    pub fn pinned_pieces(&self, color: Color) -> Vec<Position> {
+        let Some(king) = self.board.king_position(color) else {
+            return Vec::new();
         };
-        let piece_to_color = piece_to.get_color();
-        if piece_from_color == piece_to_color {
-            return Err(Error::BadMove("Cannot take your own piece".to_string()));
+        let mut pinned = Vec::new();
+        let sliding_attackers = [(&ROOK_DIRECTIONS, Rook), (&BISHOP_DIRECTIONS, Bishop)];
+        for (directions, piece_type) in sliding_attackers {
+            for &(row_delta, column_delta) in directions {
+                // The first piece on the line may be pinned by the second one
+                let mut line = std::iter::successors(king.offset(row_delta, column_delta), |current| {
+                    current.offset(row_delta, column_delta)
+                }).filter_map(|position| self.get_field(position).map(|piece| (position, piece)));
+                let (Some((shield, first)), Some((_, second))) = (line.next(), line.next()) else {
+                    continue;
+                };
+                if first.get_color() == color
+                    && second.get_color() != color
+                    && [piece_type, Queen].contains(&second.get_type()) {
+                    pinned.push(shield);
+                }
+            }
         }
-        self.move_piece(position_from, position_to);
-        Ok(Some(piece_to))
+        pinned
+    }
+
+    fn is_in_check(&self, color: Color) -> bool {
+        self.board.king_position(color).is_some_and(|position| self.is_attacked(position, color.opposite()))
     }
+
     pub fn current_player(&self) -> Turn {
         self.current_turn
     }
+
+    /// Returns true when neither side has enough material left to ever deliver a checkmate.
+    pub fn is_insufficient_material(&self) -> bool {
+        // Remaining minor pieces as (piece, color of the square it stands on)
+        let mut minor_pieces = Vec::new();
+        for (row, fields) in self.board.state.iter().enumerate() {
+            for (column, field) in fields.iter().enumerate() {
+                match field {
+                    None | Some(White(King)) | Some(Black(King)) => {},
+                    Some(piece @ (White(Bishop | Knight) | Black(Bishop | Knight))) => {
+                        minor_pieces.push((*piece, (row + column) % 2));
+                    },
+                    Some(_) => return false,
+                }
+            }
+        }
+
+        match minor_pieces.as_slice() {
+            [] | [_] => true,
+            [(White(Bishop), white_square), (Black(Bishop), black_square)] |
+            [(Black(Bishop), black_square), (White(Bishop), white_square)] => white_square == black_square,
+            _ => false,
+        }
+    }
+
+    /// Returns the material difference in pawns, positive values favor white.
+    pub fn material_balance(&self) -> i32 {
+        self.board.pieces()
+            .map(|(_, piece)| match piece {
+                White(piece_type) => piece_type.material_value(),
+                Black(piece_type) => -piece_type.material_value(),
+            })
+            .sum()
+    }
+
+    /// Returns the result of the game if it is already decided, `None` while it is still going on.
+    pub fn result(&self) -> Option<GameResult> {
+        if self.agreed_result.is_some() {
+            return self.agreed_result;
+        }
+        // Running out of time against a lone king is still a draw
+        if self.is_insufficient_material() {
+            return Some(GameResult::Draw);
+        }
+        if self.time_left(Color::White).is_zero() {
+            return Some(GameResult::BlackWins);
+        }
+        if self.time_left(Color::Black).is_zero() {
+            return Some(GameResult::WhiteWins);
+        }
+        None
+    }
+}
+
+/// Picks one of the legal moves uniformly at random, `None` once the game is over.
+pub fn random_move(state: &GameState, rng: &mut impl rand::Rng) -> Option<Move> {
+    if state.result().is_some() {
+        return None;
+    }
+    state.legal_moves().choose(rng).copied()
+}
+
+/// Counts the leaf nodes of the legal move tree of the given depth, the standard move generator check.
+pub fn perft(state: &GameState, depth: u32) -> u64 {
+    if depth == 0 {
+        return 1;
+    }
+    state.legal_moves()
+        .into_iter()
+        .map(|chess_move| {
+            // The board is small enough that copying it beats keeping an undo history
+            let mut next_state = state.clone();
+            next_state.apply_move(chess_move);
+            perft(&next_state, depth - 1)
+        })
+        .sum()
 }
 
 pub struct Player {
@@ -273,6 +876,12 @@ impl Player {
 }
 
 
+impl Default for Game {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Game {
 
     pub fn new() -> Self {
@@ -280,10 +889,7 @@ impl Game {
         let (bms, bmr) = mpsc::channel::<String>(32);  // black move sender, receiver
         let (wus, wur) = mpsc::channel::<String>(32);  // white update sender, receiver
         let (bus, bur) = mpsc::channel::<String>(32);  // black update sender, receiver
-        let game_state = Arc::new(Mutex::new(GameState {
-            board: ChessBoard::new(),  
-            current_turn: WhitePlays,
-        }));
+        let game_state = Arc::new(Mutex::new(GameState::new(ChessBoard::new())));
 
         Game {
             white_move_sender: Some(wms),
@@ -296,79 +902,200 @@ impl Game {
             black_update_receiver: Some(bur),
             game_state,
             player_created: 0,
+            spectators: Vec::new(),
+            move_timeout: None,
         }
     }
 
-    pub fn create_player(&mut self) -> Player {
-        self.player_created += 1;
-        match self.player_created {
-            1 => {
+    pub fn create_player(&mut self) -> Result<Player, Error> {
+        let player = match self.player_created {
+            0 => {
                 Player {
                     sender: self.white_move_sender.take().expect("White move sender already taken"),
                     receiver: self.white_update_receiver.take().expect("White update receiver already taken"),
                     color: Color::White,
                 }
             },
-            2 => {
+            1 => {
                 Player {
                     sender: self.black_move_sender.take().expect("Black move sender already taken"),
                     receiver: self.black_update_receiver.take().expect("Black update receiver already taken"),
                     color: Color::Black,
                 }
             },
-            _ => panic!("All players have already been created"),
-        }
+            _ => return Err(Error::Other("All players have already been created".to_string())),
+        };
+        self.player_created += 1;
+        Ok(player)
+    }
+
+    /// Registers a read-only observer that receives every accepted move and the result of the game.
+    pub fn add_spectator(&mut self) -> mpsc::Receiver<String> {
+        let (sender, receiver) = mpsc::channel::<String>(32);
+        self.spectators.push(sender);
+        receiver
+    }
+
+    fn notify_spectators(&mut self, move_str: &str) {
+        // A slow spectator must never stall the game, so full queues just miss the move
+        // and spectators that hung up are forgotten
+        self.spectators.retain(|spectator| {
+            !matches!(spectator.try_send(move_str.to_string()), Err(mpsc::error::TrySendError::Closed(_)))
+        });
+    }
+
+    /// Makes the side to move lose once it thinks about a single move for longer than the timeout.
+    pub fn set_move_timeout(&mut self, timeout: Duration) {
+        self.move_timeout = Some(timeout);
     }
 
     pub async fn run(&mut self) {
+        self.game_state.lock().await.start_clock(Instant::now());
+        let mut waiting_for = None;
+        let mut deadline = tokio::time::Instant::now();
+        let (mut white_connected, mut black_connected) = (true, true);
         loop {
+            // Both players are gone, nobody is left to play
+            if !white_connected && !black_connected {
+                break;
+            }
+            // Rejected moves and messages of the other side do not give the side to move more time
+            let (side_to_move, flag_fall) = {
+                let game_state = self.game_state.lock().await;
+                let side_to_move = game_state.current_player().get_color();
+                let flag_fall = game_state.clock_started.map(|started| started + game_state.time_left(side_to_move));
+                (side_to_move, flag_fall)
+            };
+            if waiting_for != Some(side_to_move) {
+                waiting_for = Some(side_to_move);
+                deadline = tokio::time::Instant::now() + self.move_timeout.unwrap_or_default();
+            }
             tokio::select! {
-                Some(move_str) = self.white_move_receiver.recv() => {
+                move_str = self.white_move_receiver.recv(), if white_connected => {
+                    let Some(move_str) = move_str else {
+                        white_connected = false;
+                        continue;
+                    };
                     println!("White move: {}", move_str);
-                    let result = self.handle_move(move_str.clone()).await;
+                    let result = self.handle_move(move_str.clone(), Color::White).await;
                     match result {
                         Ok(_) => {
                             // If the move is valid, send it to the black player
                             let _ = self.white_update_sender.send("Move accepted".to_string()).await;
+                            if !GAME_MESSAGES.contains(&move_str.as_str()) {
+                                self.notify_spectators(&move_str);
+                            }
                             let _ = self.black_update_sender.send(move_str).await;
+                            if self.announce_result().await {
+                                break;
+                            }
+                            self.game_state.lock().await.start_clock(Instant::now());
                         },
                         Err(e) => {
                             // Send error back to white player
                             let _ = self.white_update_sender.send(e.to_string()).await;
+                            // The move came too late if the clock of the mover ran out meanwhile
+                            if self.announce_result().await {
+                                break;
+                            }
+                            self.game_state.lock().await.start_clock(Instant::now());
                         }
                     }
                 },
-                Some(move_str) = self.black_move_receiver.recv() => {
+                move_str = self.black_move_receiver.recv(), if black_connected => {
+                    let Some(move_str) = move_str else {
+                        black_connected = false;
+                        continue;
+                    };
                     println!("Black move: {}", move_str);
-                    let result = self.handle_move(move_str.clone()).await;
+                    let result = self.handle_move(move_str.clone(), Color::Black).await;
                     match result {
                         Ok(_) => {
                             // If the move is valid, send it to the white player
                             let _ = self.black_update_sender.send("Move accepted".to_string()).await;
+                            if !GAME_MESSAGES.contains(&move_str.as_str()) {
+                                self.notify_spectators(&move_str);
+                            }
                             let _ = self.white_update_sender.send(move_str).await;
+                            if self.announce_result().await {
+                                break;
+                            }
+                            self.game_state.lock().await.start_clock(Instant::now());
                         },
                         Err(e) => {
                             // Send error back to black player
                             let _ = self.black_update_sender.send(e.to_string()).await;
+                            // The move came too late if the clock of the mover ran out meanwhile
+                            if self.announce_result().await {
+                                break;
+                            }
+                            self.game_state.lock().await.start_clock(Instant::now());
                         }
                     }
                 },
+                // The clock of the side to move runs out even if nobody sends anything
+                _ = tokio::time::sleep_until(flag_fall.map_or(deadline, tokio::time::Instant::from_std)), if flag_fall.is_some() => {
+                    println!("Flag fall");
+                    self.game_state.lock().await.stop_clock(Instant::now());
+                    if self.announce_result().await {
+                        break;
+                    }
+                    self.game_state.lock().await.start_clock(Instant::now());
+                },
+                _ = tokio::time::sleep_until(deadline), if self.move_timeout.is_some() => {
+                    println!("Move timeout");
+                    // Running out of time for a move forfeits the game just like resigning
+                    self.game_state.lock().await.resign(side_to_move);
+                    self.announce_result().await;
+                    break;
+                },
+                else => break,
             }
         }
     }
     
 
-    async fn handle_move(&self, move_str: String) -> Result<(), Error> {
-        println!("Handling move: {}", move_str);
-        let parts: Vec<&str> = move_str.split('-').collect();
-        if parts.len() != 2 {
-            return Err(Error::Other("Invalid move format".to_string()));
-        }
+    // Tells everybody how the game ended, returns false while it is still going on
+    async fn announce_result(&mut self) -> bool {
+        let Some(result) = self.game_state.lock().await.result() else {
+            return false;
+        };
+        let message = format!("Game over: {}", result);
+        self.notify_spectators(&message);
+        let _ = self.white_update_sender.send(message.clone()).await;
+        let _ = self.black_update_sender.send(message).await;
+        true
+    }
 
-        let from_pos = parts[0].try_into().map_err(|_| Error::Other("Invalid start position".to_string()))?;
-        let to_pos = parts[1].try_into().map_err(|_| Error::Other("Invalid end position".to_string()))?;
+    async fn handle_move(&self, move_str: String, color: Color) -> Result<(), Error> {
+        println!("Handling move: {}", move_str);
+        let chess_move = match move_str.as_str() {
+            message if GAME_MESSAGES.contains(&message) => None,
+            _ => Some(Move::try_from(move_str.as_str())?),
+        };
 
         let mut game_state = self.game_state.lock().await;  // Await the lock here
-        game_state.make_move(from_pos, to_pos).await.map(|_| ())
+        let now = Instant::now();
+        game_state.stop_clock(now);
+        if game_state.result().is_some() {
+            return Err(Error::BadMove("The game is already over".to_string()));
+        }
+        let result = match (chess_move, move_str.as_str()) {
+            (Some(chess_move), _) => game_state.make_move(chess_move).await.map(|_| ()),
+            (None, "resign") => {
+                game_state.resign(color);
+                Ok(())
+            },
+            (None, "offer-draw") => {
+                game_state.offer_draw(color);
+                Ok(())
+            },
+            (None, _) => game_state.accept_draw(color),
+        };
+        if result.is_err() {
+            // The side to move keeps thinking after a rejected move
+            game_state.start_clock(now);
+        }
+        result
     }
 }
\ No newline at end of file
@@ -1,5 +1,9 @@
+// The demo in main uses only a part of the library, the rest is covered by the tests
+#![cfg_attr(not(test), allow(dead_code))]
+
 use std::rc::Rc;
 use std::collections::HashMap;
+use std::fmt;
 pub trait Expression {
     fn accept(&self, visitor: &mut dyn Visitor);
     fn as_ref(&self) -> &dyn Expression;
@@ -22,9 +26,6 @@ impl Const {
         Const { value }
     }
     pub fn value(&self) -> i32 { self.value }
-    pub fn as_ref(&self) -> &dyn Expression {
-        self
-    }
 }
 
 impl Expression for Const {
@@ -44,9 +45,6 @@ impl Variable {
         Variable { name }
     }
     pub fn name(&self) -> &str { &self.name }
-    pub fn as_ref(&self) -> &dyn Expression {
-        self
-    }
 }
 
 impl Expression for Variable {
@@ -77,12 +75,15 @@ impl Sum {
     }
     pub fn left(&self) -> &dyn Expression { self.left.as_ref() }
     pub fn right(&self) -> &dyn Expression { self.right.as_ref() }
-    pub fn as_ref(&self) -> &dyn Expression {
-        self
-    }
 }
 
 
+impl Drop for Sum {
+    fn drop(&mut self) {
+        drop_operands([&mut self.left, &mut self.right]);
+    }
+}
+
 impl Expression for Sum {
     fn accept(&self, visitor: &mut dyn Visitor) {
         visitor.visit_sum(self);
@@ -96,6 +97,43 @@ impl Expression for Sum {
 }
 
 
+#[derive(Clone)]
+pub struct Difference {
+    left: Rc<dyn Expression>,
+    right: Rc<dyn Expression>,
+}
+
+impl Difference {
+    pub fn new<L: Expression + 'static, R: Expression + 'static>(left: L, right: R) -> Self {
+        Difference {
+            left: Rc::new(left),
+            right: Rc::new(right),
+        }
+    }
+    pub fn left(&self) -> &dyn Expression { self.left.as_ref() }
+    pub fn right(&self) -> &dyn Expression { self.right.as_ref() }
+}
+
+
+impl Drop for Difference {
+    fn drop(&mut self) {
+        drop_operands([&mut self.left, &mut self.right]);
+    }
+}
+
+impl Expression for Difference {
+    fn accept(&self, visitor: &mut dyn Visitor) {
+        visitor.visit_difference(self);
+    }
+    fn as_ref(&self) -> &dyn Expression {
+        self
+    }
+    fn rc_clone(&self) -> Rc<dyn Expression> {
+        Rc::new(self.clone())
+    }
+}
+
+
 #[derive(Clone)]
 pub struct Product {
     left: Rc<dyn Expression>,
@@ -111,12 +149,15 @@ impl Product {
     }
     pub fn left(&self) -> &dyn Expression { self.left.as_ref() }
     pub fn right(&self) -> &dyn Expression { self.right.as_ref() }
-    pub fn as_ref(&self) -> &dyn Expression {
-        self
-    }
 }
 
 
+impl Drop for Product {
+    fn drop(&mut self) {
+        drop_operands([&mut self.left, &mut self.right]);
+    }
+}
+
 impl Expression for Product {
     fn accept(&self, visitor: &mut dyn Visitor) {
         visitor.visit_product(self);
@@ -129,6 +170,78 @@ impl Expression for Product {
     }
 }
 
+#[derive(Clone)]
+pub struct Quotient {
+    left: Rc<dyn Expression>,
+    right: Rc<dyn Expression>,
+}
+
+impl Quotient {
+    pub fn new<L: Expression + 'static, R: Expression + 'static>(left: L, right: R) -> Self {
+        Quotient {
+            left: Rc::new(left),
+            right: Rc::new(right),
+        }
+    }
+    pub fn left(&self) -> &dyn Expression { self.left.as_ref() }
+    pub fn right(&self) -> &dyn Expression { self.right.as_ref() }
+}
+
+
+impl Drop for Quotient {
+    fn drop(&mut self) {
+        drop_operands([&mut self.left, &mut self.right]);
+    }
+}
+
+impl Expression for Quotient {
+    fn accept(&self, visitor: &mut dyn Visitor) {
+        visitor.visit_quotient(self);
+    }
+    fn as_ref(&self) -> &dyn Expression {
+        self
+    }
+    fn rc_clone(&self) -> Rc<dyn Expression> {
+        Rc::new(self.clone())
+    }
+}
+
+#[derive(Clone)]
+pub struct Power {
+    base: Rc<dyn Expression>,
+    exponent: Rc<dyn Expression>,
+}
+
+impl Power {
+    pub fn new<B: Expression + 'static, E: Expression + 'static>(base: B, exponent: E) -> Self {
+        Power {
+            base: Rc::new(base),
+            exponent: Rc::new(exponent),
+        }
+    }
+    pub fn base(&self) -> &dyn Expression { self.base.as_ref() }
+    pub fn exponent(&self) -> &dyn Expression { self.exponent.as_ref() }
+}
+
+
+impl Drop for Power {
+    fn drop(&mut self) {
+        drop_operands([&mut self.base, &mut self.exponent]);
+    }
+}
+
+impl Expression for Power {
+    fn accept(&self, visitor: &mut dyn Visitor) {
+        visitor.visit_power(self);
+    }
+    fn as_ref(&self) -> &dyn Expression {
+        self
+    }
+    fn rc_clone(&self) -> Rc<dyn Expression> {
+        Rc::new(self.clone())
+    }
+}
+
 pub struct PostfixConvertor {
     result: Vec<String>,
 }
@@ -137,178 +250,1365 @@ pub trait Visitor {
     fn visit_const(&mut self, cst: &Const);
     fn visit_var(&mut self, var: &Variable);
     fn visit_sum(&mut self, sum: &Sum);
+    fn visit_difference(&mut self, difference: &Difference);
     fn visit_product(&mut self, product: &Product);
+    fn visit_quotient(&mut self, quotient: &Quotient);
+    fn visit_power(&mut self, power: &Power);
+}
+
+// Like Visitor, but every method returns its result instead of storing it in the visitor
+pub trait Folder {
+    type Output;
+
+    fn fold_const(&mut self, cst: &Const) -> Self::Output;
+    fn fold_var(&mut self, var: &Variable) -> Self::Output;
+    fn fold_sum(&mut self, sum: &Sum) -> Self::Output;
+    fn fold_difference(&mut self, difference: &Difference) -> Self::Output;
+    fn fold_product(&mut self, product: &Product) -> Self::Output;
+    fn fold_quotient(&mut self, quotient: &Quotient) -> Self::Output;
+    fn fold_power(&mut self, power: &Power) -> Self::Output;
+}
+
+impl dyn Expression + '_ {
+    pub fn accept_fold<F: Folder>(&self, folder: &mut F) -> F::Output {
+        let mut adapter = FoldAdapter { folder, result: None };
+        self.accept(&mut adapter);
+        adapter.result.unwrap()
+    }
+}
+
+// Runs a Folder through the Visitor based accept
+struct FoldAdapter<'f, F: Folder> {
+    folder: &'f mut F,
+    result: Option<F::Output>,
+}
+
+impl<F: Folder> Visitor for FoldAdapter<'_, F> {
+    fn visit_const(&mut self, cst: &Const) {
+        self.result = Some(self.folder.fold_const(cst));
+    }
+
+    fn visit_var(&mut self, var: &Variable) {
+        self.result = Some(self.folder.fold_var(var));
+    }
+
+    fn visit_sum(&mut self, sum: &Sum) {
+        self.result = Some(self.folder.fold_sum(sum));
+    }
+
+    fn visit_difference(&mut self, difference: &Difference) {
+        self.result = Some(self.folder.fold_difference(difference));
+    }
+
+    fn visit_product(&mut self, product: &Product) {
+        self.result = Some(self.folder.fold_product(product));
+    }
+
+    fn visit_quotient(&mut self, quotient: &Quotient) {
+        self.result = Some(self.folder.fold_quotient(quotient));
+    }
+
+    fn visit_power(&mut self, power: &Power) {
+        self.result = Some(self.folder.fold_power(power));
+    }
 }
 
 
 impl PostfixConvertor {
     pub fn transform(expression: &dyn Expression) -> String {
         let mut visitor = Self::new();
-        expression.accept(&mut visitor);
+        walk_postfix(expression, |node| {
+            visitor.push(node);
+            Ok::<(), ()>(())
+        }).unwrap();
         visitor.consume()
     }
     fn new() -> Self {
         PostfixConvertor { result: Vec::new() }
     }
     fn consume(self) -> String { self.result.join(" ") }
+
+    fn push(&mut self, node: Node) {
+        self.result.push(match node {
+            Node::Const(value) => value.to_string(),
+            Node::Variable(name) => name,
+            Node::Sum => "+".to_string(),
+            Node::Difference => "-".to_string(),
+            Node::Product => "*".to_string(),
+            Node::Quotient => "/".to_string(),
+            Node::Power => "^".to_string(),
+        });
+    }
+}
+
+// Inverse of PostfixConvertor::transform, tokens are separated by whitespace
+pub fn parse_postfix(input: &str) -> Result<Rc<dyn Expression>, String> {
+    let mut stack: Vec<Rc<dyn Expression>> = Vec::new();
+    for token in input.split_whitespace() {
+        let expression: Rc<dyn Expression> = match token {
+            "+" | "-" | "*" | "/" | "^" => {
+                let (Some(right), Some(left)) = (stack.pop(), stack.pop()) else {
+                    return Err(format!("Missing operand for '{}'", token));
+                };
+                match token {
+                    "+" => Rc::new(Sum{left, right}),
+                    "-" => Rc::new(Difference{left, right}),
+                    "*" => Rc::new(Product{left, right}),
+                    "/" => Rc::new(Quotient{left, right}),
+                    _ => Rc::new(Power{base: left, exponent: right}),
+                }
+            }
+            _ => match token.parse::<i32>() {
+                Ok(value) => Rc::new(Const::new(value)),
+                Err(_) => Rc::new(Variable::new(token.to_string())),
+            },
+        };
+        stack.push(expression);
+    }
+
+    match stack.len() {
+        0 => Err("Empty expression".to_string()),
+        1 => Ok(stack.pop().unwrap()),
+        count => Err(format!("Missing operators for {} operands", count - 1)),
+    }
+}
+
+// Builds an Rc<dyn Expression> from infix notation, e.g. expr!((x + 2) * y) with the usual precedence
+macro_rules! expr {
+    // Splits the tokens into terms separated by the top level `+`
+    (@sum ($($terms:tt)*) [$($term:tt)*] + $($rest:tt)*) => {
+        expr!(@sum ($($terms)* [$($term)*]) [] $($rest)*)
+    };
+    (@sum ($($terms:tt)*) [$($term:tt)*] $token:tt $($rest:tt)*) => {
+        expr!(@sum ($($terms)*) [$($term)* $token] $($rest)*)
+    };
+    (@sum ([$($first:tt)*] $([$($term:tt)*])*) []) => {{
+        let expression: std::rc::Rc<dyn $crate::Expression> = expr!(@product $($first)*);
+        $(
+            let expression: std::rc::Rc<dyn $crate::Expression> = std::rc::Rc::new($crate::Sum {
+                left: expression,
+                right: expr!(@product $($term)*),
+            });
+        )*
+        expression
+    }};
+    (@sum ($($terms:tt)*) [$($term:tt)+]) => {
+        expr!(@sum ($($terms)* [$($term)+]) [])
+    };
+    (@product $first:tt $(* $factor:tt)*) => {{
+        let expression: std::rc::Rc<dyn $crate::Expression> = expr!(@factor $first);
+        $(
+            let expression: std::rc::Rc<dyn $crate::Expression> = std::rc::Rc::new($crate::Product {
+                left: expression,
+                right: expr!(@factor $factor),
+            });
+        )*
+        expression
+    }};
+    (@factor ($($inner:tt)+)) => { expr!($($inner)+) };
+    (@factor $value:literal) => { std::rc::Rc::new($crate::Const::new($value)) };
+    (@factor $name:ident) => { std::rc::Rc::new($crate::Variable::new(stringify!($name).to_string())) };
+    ($($tokens:tt)+) => { expr!(@sum () [] $($tokens)+) };
+}
+
+pub struct InfixConvertor {
+    result: String,
+    operator: Option<char>,
+}
+
+impl InfixConvertor {
+    pub fn transform(expression: &dyn Expression) -> String {
+        let mut visitor = InfixConvertor { result: String::new(), operator: None };
+        expression.accept(&mut visitor);
+        visitor.result
+    }
+
+    // Binding strength of the operator at the top of a rendered expression, constants and variables bind the most
+    fn precedence(operator: Option<char>) -> u8 {
+        match operator {
+            Some('+') | Some('-') => 1,
+            Some('*') | Some('/') => 2,
+            Some(_) => 3,
+            None => 4,
+        }
+    }
+
+    fn render(&mut self, expression: &dyn Expression) -> (String, Option<char>) {
+        expression.accept(self);
+        (std::mem::take(&mut self.result), self.operator.take())
+    }
+
+    // Parenthesizes an operand only when leaving the parentheses out would change its meaning
+    fn binary(&mut self, left: &dyn Expression, right: &dyn Expression, operator: char) {
+        let precedence = Self::precedence(Some(operator));
+        let (mut left, left_operator) = self.render(left);
+        let (mut right, right_operator) = self.render(right);
+
+        let left_precedence = Self::precedence(left_operator);
+        if left_precedence < precedence || (operator == '^' && left_operator == Some('^')) {
+            left = format!("({})", left);
+        }
+        let right_precedence = Self::precedence(right_operator);
+        let associative = matches!((operator, right_operator), ('+', _) | ('*', Some('*')) | ('^', Some('^')));
+        if right_precedence < precedence || (right_precedence == precedence && !associative) {
+            right = format!("({})", right);
+        }
+        self.result = format!("{} {} {}", left, operator, right);
+        self.operator = Some(operator);
+    }
 }
 
-impl Visitor for PostfixConvertor {
+impl Visitor for InfixConvertor {
     fn visit_const(&mut self, cst: &Const) {
-        self.result.push(format!("{}", cst.value()));
+        self.result = cst.value().to_string();
     }
 
     fn visit_var(&mut self, var: &Variable) {
-        self.result.push(format!("{}", var.name()));
+        self.result = var.name().to_string();
     }
 
     fn visit_sum(&mut self, sum: &Sum) {
-        sum.left().accept(self);
-        sum.right().accept(self);
-        self.result.push(format!("+"));
+        self.binary(sum.left(), sum.right(), '+');
+    }
+
+    fn visit_difference(&mut self, difference: &Difference) {
+        self.binary(difference.left(), difference.right(), '-');
     }
 
     fn visit_product(&mut self, product: &Product) {
-        product.left().accept(self);
-        product.right().accept(self);
-        self.result.push(format!("*"));
+        self.binary(product.left(), product.right(), '*');
+    }
+
+    fn visit_quotient(&mut self, quotient: &Quotient) {
+        self.binary(quotient.left(), quotient.right(), '/');
+    }
+
+    fn visit_power(&mut self, power: &Power) {
+        self.binary(power.base(), power.exponent(), '^');
     }
 }
 
-pub struct Evaluate<'a> {
-    result: Result<i32, String>,
-    values: &'a HashMap<String, i32>,
+// Tagged JSON AST, e.g. {"op":"sum","left":{"op":"var","name":"x"},"right":{"op":"const","value":1}}
+pub struct JsonConvertor {
+    result: String,
 }
 
-impl<'a> Evaluate<'a> {
-    pub fn transform(expression: &dyn Expression, values: &'a HashMap<String, i32>) -> Result<i32, String> {
-        let mut evaluator = Evaluate { result: Ok(0), values };
-        expression.accept(&mut evaluator);
-        evaluator.result
+impl JsonConvertor {
+    pub fn transform(expression: &dyn Expression) -> String {
+        let mut visitor = JsonConvertor { result: String::new() };
+        expression.accept(&mut visitor);
+        visitor.result
+    }
+
+    fn binary(&mut self, op: &str, operands: [(&str, &dyn Expression); 2]) {
+        self.result.push_str(&format!("{{\"op\":\"{}\"", op));
+        for (key, operand) in operands {
+            self.result.push_str(&format!(",\"{}\":", key));
+            operand.accept(self);
+        }
+        self.result.push('}');
     }
 }
 
-impl<'a> Visitor for Evaluate<'a> {
+impl Visitor for JsonConvertor {
     fn visit_const(&mut self, cst: &Const) {
-        if let Ok(ref mut result) = self.result {
-            *result = cst.value(); 
-        }
+        self.result.push_str(&format!("{{\"op\":\"const\",\"value\":{}}}", cst.value()));
     }
 
     fn visit_var(&mut self, var: &Variable) {
-        if let Ok(ref mut result) = self.result {
-            *result = match self.values.get(var.name()) { 
-                Some(&value) => value,
-                None => {
-                    self.result = Err(format!("Missing variable {}", var.name()));
-                    return;
-                }
-            };
+        let mut name = String::new();
+        for c in var.name().chars() {
+            match c {
+                '\\' => name.push_str("\\\\"),
+                '"' => name.push_str("\\\""),
+                '\n' => name.push_str("\\n"),
+                '\t' => name.push_str("\\t"),
+                // JSON strings cannot hold any other control character as it is
+                c if c < ' ' => name.push_str(&format!("\\u{:04x}", c as u32)),
+                c => name.push(c),
+            }
         }
+        self.result.push_str(&format!("{{\"op\":\"var\",\"name\":\"{}\"}}", name));
     }
+
     fn visit_sum(&mut self, sum: &Sum) {
-        if self.result.is_err() {
-            return;
-        }
-    
-        sum.left().accept(self);
-        let left = match &self.result {
-            Ok(value) => *value,
-            Err(_) => return,
-        };
-    
-        sum.right().accept(self);
-        let right = match &self.result {
-            Ok(value) => *value,
-            Err(_) => return,
-        };
-    
-        self.result = Ok(left + right);
+        self.binary("sum", [("left", sum.left()), ("right", sum.right())]);
+    }
+
+    fn visit_difference(&mut self, difference: &Difference) {
+        self.binary("difference", [("left", difference.left()), ("right", difference.right())]);
     }
 
     fn visit_product(&mut self, product: &Product) {
-        if self.result.is_err() {
-            return;
-        }
-    
-        product.left().accept(self);
-        let left = match &self.result {
-            Ok(value) => *value,
-            Err(_) => return,
-        };
-    
-        product.right().accept(self);
-        let right = match &self.result {
-            Ok(value) => *value,
-            Err(_) => return,
-        };
-    
-        self.result = Ok(left * right);
+        self.binary("product", [("left", product.left()), ("right", product.right())]);
+    }
+
+    fn visit_quotient(&mut self, quotient: &Quotient) {
+        self.binary("quotient", [("left", quotient.left()), ("right", quotient.right())]);
+    }
+
+    fn visit_power(&mut self, power: &Power) {
+        self.binary("power", [("base", power.base()), ("exponent", power.exponent())]);
     }
 }
 
+enum Json {
+    Number(i32),
+    Text(String),
+    Object(HashMap<String, Json>),
+}
 
-pub struct Substitute<'a> {
-    result: Option<Rc<dyn Expression>>,
-    values: &'a HashMap<String, &'a dyn Expression>,
+// Reads the subset of JSON produced by JsonConvertor
+struct JsonParser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
 }
 
-impl<'a> Substitute<'a> {
-    pub fn transform(expression: &'a dyn Expression, values: &'a HashMap<String, &'a dyn Expression>) -> Rc<dyn Expression> {
-        let mut substitute  = Substitute { result: None, values };
-        expression.accept(&mut substitute);
-        substitute.result.unwrap()
+impl JsonParser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
     }
-}
 
-impl<'a> Visitor for Substitute<'a> {
-    fn visit_const(&mut self, cst: &Const) {
-        self.result = Some(Rc::new(cst.clone()));
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(format!("Expected '{}', found '{}'", expected, c)),
+            None => Err(format!("Expected '{}', found the end of input", expected)),
+        }
     }
 
-    fn visit_var(&mut self, var: &Variable) {
-        if let Some(&expr) = self.values.get(&var.name) {
-            self.result = Some((*expr).rc_clone());
-        } else {
-            self.result = Some(Rc::new(var.clone()));
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some('{') => self.object(),
+            Some('"') => self.text().map(Json::Text),
+            Some(c) if *c == '-' || c.is_ascii_digit() => self.number(),
+            Some(c) => Err(format!("Unexpected '{}'", c)),
+            None => Err("Unexpected end of input".to_string()),
         }
     }
-    fn visit_sum(&mut self, sum: &Sum) {
-        sum.left().accept(self);
-        let left = self.result.clone().unwrap();
-    
-        sum.right().accept(self);
-        let right = self.result.clone().unwrap();
-        self.result = Some(Rc::new(Sum{left, right}));
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.expect('{')?;
+        let mut fields = HashMap::new();
+        self.skip_whitespace();
+        if self.chars.next_if_eq(&'}').is_some() {
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.text()?;
+            self.expect(':')?;
+            fields.insert(key, self.value()?);
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(Json::Object(fields)),
+                _ => return Err("Expected ',' or '}'".to_string()),
+            }
+        }
     }
 
-    fn visit_product(&mut self, product: &Product) {
-        product.left().accept(self);
-        let left = self.result.clone().unwrap();
-    
-        product.right().accept(self);
-        let right = self.result.clone().unwrap();
-        self.result = Some(Rc::new(Product{left, right}));
+    fn text(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut text = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(text),
+                Some('\\') => match self.chars.next() {
+                    Some(c @ ('"' | '\\' | '/')) => text.push(c),
+                    Some('n') => text.push('\n'),
+                    Some('t') => text.push('\t'),
+                    Some('r') => text.push('\r'),
+                    Some('u') => {
+                        let code: String = self.chars.by_ref().take(4).collect();
+                        let c = u32::from_str_radix(&code, 16).ok()
+                            .filter(|_| code.len() == 4)
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| format!("Invalid unicode escape \"\\u{}\"", code))?;
+                        text.push(c);
+                    },
+                    _ => return Err("Unsupported escape sequence".to_string()),
+                },
+                Some(c) => text.push(c),
+                None => return Err("Unterminated string".to_string()),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let mut number = String::new();
+        if let Some(sign) = self.chars.next_if_eq(&'-') {
+            number.push(sign);
+        }
+        while let Some(digit) = self.chars.next_if(|c| c.is_ascii_digit()) {
+            number.push(digit);
+        }
+        number.parse().map(Json::Number).map_err(|_| format!("Invalid number {}", number))
     }
 }
 
-fn main() {
-    let c = Const::new(27);
-    let v = Variable::new("a".to_string()); 
-    let s = Sum::new(c.clone(), v.clone());
-    let s1 = Sum::new(s.clone(), v.clone());
-    let s3 = Sum::new(v, s1.clone());
-    let p = Product::new(s3.clone(), c.clone());
-    println!("{}", PostfixConvertor::transform(p.as_ref()));
-    let values = HashMap::from([("a".to_string(), 42)]);
-    match Evaluate::transform(p.as_ref(), &values) {
-        Ok(value) => println!("{}", value),
-        Err(_) => println!("Missing variable."),
+impl Json {
+    fn field(&self, key: &str) -> Result<&Json, String> {
+        match self {
+            Json::Object(fields) => fields.get(key).ok_or_else(|| format!("Missing field \"{}\"", key)),
+            _ => Err("Expected an object".to_string()),
+        }
     }
 
-    let a = Variable::new("test".to_string());
-    let mut values: HashMap<_, &dyn Expression> = HashMap::new();
-    values.insert("a".to_string(), p.as_ref());
-    let exp = Substitute::transform(p.as_ref(), &values);
-    println!("{}", PostfixConvertor::transform(exp.as_ref()));
+    fn to_expression(&self) -> Result<Rc<dyn Expression>, String> {
+        let operand = |key: &str| self.field(key)?.to_expression();
+        let op = match self.field("op")? {
+            Json::Text(op) => op.as_str(),
+            _ => return Err("The field \"op\" has to be a string".to_string()),
+        };
+        Ok(match op {
+            "const" => match self.field("value")? {
+                Json::Number(value) => Rc::new(Const::new(*value)),
+                _ => return Err("The field \"value\" has to be a number".to_string()),
+            },
+            "var" => match self.field("name")? {
+                Json::Text(name) => Rc::new(Variable::new(name.clone())),
+                _ => return Err("The field \"name\" has to be a string".to_string()),
+            },
+            "sum" => Rc::new(Sum{left: operand("left")?, right: operand("right")?}),
+            "difference" => Rc::new(Difference{left: operand("left")?, right: operand("right")?}),
+            "product" => Rc::new(Product{left: operand("left")?, right: operand("right")?}),
+            "quotient" => Rc::new(Quotient{left: operand("left")?, right: operand("right")?}),
+            "power" => Rc::new(Power{base: operand("base")?, exponent: operand("exponent")?}),
+            op => return Err(format!("Unknown operation \"{}\"", op)),
+        })
+    }
+}
+
+// Inverse of JsonConvertor::transform
+pub fn parse_json(input: &str) -> Result<Rc<dyn Expression>, String> {
+    let mut parser = JsonParser { chars: input.chars().peekable() };
+    let json = parser.value()?;
+    parser.skip_whitespace();
+    if parser.chars.peek().is_some() {
+        return Err("Unexpected input after the expression".to_string());
+    }
+    json.to_expression()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EvalError {
+    MissingVariable(String),
+    DivisionByZero,
+    Overflow,
+    NegativeExponent(i32),
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvalError::MissingVariable(name) => write!(f, "Missing variable {}", name),
+            EvalError::DivisionByZero => write!(f, "Division by zero"),
+            EvalError::Overflow => write!(f, "Overflow"),
+            EvalError::NegativeExponent(exponent) => write!(f, "Negative exponent {}", exponent),
+        }
+    }
+}
+
+pub struct Evaluate<'a> {
+    values: &'a HashMap<String, i32>,
+}
+
+impl<'a> Evaluate<'a> {
+    pub fn new(values: &'a HashMap<String, i32>) -> Self {
+        Evaluate { values }
+    }
+
+    pub fn transform(expression: &dyn Expression, values: &'a HashMap<String, i32>) -> Result<i32, EvalError> {
+        Evaluate::new(values).evaluate(expression)
+    }
+
+    // Walks the tree with an explicit stack of the operand values, so deep trees do not overflow the call stack
+    fn evaluate(&self, expression: &dyn Expression) -> Result<i32, EvalError> {
+        let mut results = Vec::new();
+        walk_postfix(expression, |node| {
+            let value = match node {
+                Node::Const(value) => value,
+                Node::Variable(name) => match self.values.get(&name) {
+                    Some(&value) => value,
+                    None => return Err(EvalError::MissingVariable(name)),
+                },
+                operation => {
+                    let right = results.pop().unwrap();
+                    let left = results.pop().unwrap();
+                    Self::apply(&operation, left, right)?
+                }
+            };
+            results.push(value);
+            Ok(())
+        })?;
+        Ok(results.pop().unwrap())
+    }
+
+    fn apply(operation: &Node, left: i32, right: i32) -> Result<i32, EvalError> {
+        match operation {
+            Node::Sum => left.checked_add(right).ok_or(EvalError::Overflow),
+            Node::Difference => left.checked_sub(right).ok_or(EvalError::Overflow),
+            Node::Product => left.checked_mul(right).ok_or(EvalError::Overflow),
+            // Integer division rounding towards zero
+            Node::Quotient if right == 0 => Err(EvalError::DivisionByZero),
+            Node::Quotient => left.checked_div(right).ok_or(EvalError::Overflow),
+            // Only non-negative exponents are supported as the result has to stay an integer
+            Node::Power => match u32::try_from(right) {
+                Ok(exponent) => left.checked_pow(exponent).ok_or(EvalError::Overflow),
+                Err(_) => Err(EvalError::NegativeExponent(right)),
+            },
+            Node::Const(_) | Node::Variable(_) => unreachable!("Not a binary operation"),
+        }
+    }
+}
+
+// Every node hands its whole subtree to the iterative evaluation, so folding deep trees is stack safe as well
+impl<'a> Folder for Evaluate<'a> {
+    type Output = Result<i32, EvalError>;
+
+    fn fold_const(&mut self, cst: &Const) -> Self::Output {
+        self.evaluate(cst)
+    }
+
+    fn fold_var(&mut self, var: &Variable) -> Self::Output {
+        self.evaluate(var)
+    }
+
+    fn fold_sum(&mut self, sum: &Sum) -> Self::Output {
+        self.evaluate(sum)
+    }
+
+    fn fold_difference(&mut self, difference: &Difference) -> Self::Output {
+        self.evaluate(difference)
+    }
+
+    fn fold_product(&mut self, product: &Product) -> Self::Output {
+        self.evaluate(product)
+    }
+
+    fn fold_quotient(&mut self, quotient: &Quotient) -> Self::Output {
+        self.evaluate(quotient)
+    }
+
+    fn fold_power(&mut self, power: &Power) -> Self::Output {
+        self.evaluate(power)
+    }
+}
+
+pub struct Substitute<'a> {
+    result: Option<Rc<dyn Expression>>,
+    values: &'a HashMap<String, &'a dyn Expression>,
+}
+
+impl<'a> Substitute<'a> {
+    pub fn transform(expression: &'a dyn Expression, values: &'a HashMap<String, &'a dyn Expression>) -> Rc<dyn Expression> {
+        let mut substitute  = Substitute { result: None, values };
+        expression.accept(&mut substitute);
+        substitute.result.unwrap()
+    }
+}
+
+impl<'a> Visitor for Substitute<'a> {
+    fn visit_const(&mut self, cst: &Const) {
+        self.result = Some(Rc::new(cst.clone()));
+    }
+
+    fn visit_var(&mut self, var: &Variable) {
+        if let Some(&expr) = self.values.get(&var.name) {
+            self.result = Some((*expr).rc_clone());
+        } else {
+            self.result = Some(Rc::new(var.clone()));
+        }
+    }
+    fn visit_sum(&mut self, sum: &Sum) {
+        sum.left().accept(self);
+        let left = self.result.clone().unwrap();
+    
+        sum.right().accept(self);
+        let right = self.result.clone().unwrap();
+        self.result = Some(Rc::new(Sum{left, right}));
+    }
+
+    fn visit_difference(&mut self, difference: &Difference) {
+        difference.left().accept(self);
+        let left = self.result.clone().unwrap();
+    
+        difference.right().accept(self);
+        let right = self.result.clone().unwrap();
+        self.result = Some(Rc::new(Difference{left, right}));
+    }
+
+    fn visit_product(&mut self, product: &Product) {
+        product.left().accept(self);
+        let left = self.result.clone().unwrap();
+    
+        product.right().accept(self);
+        let right = self.result.clone().unwrap();
+        self.result = Some(Rc::new(Product{left, right}));
+    }
+
+    fn visit_quotient(&mut self, quotient: &Quotient) {
+        quotient.left().accept(self);
+        let left = self.result.clone().unwrap();
+    
+        quotient.right().accept(self);
+        let right = self.result.clone().unwrap();
+        self.result = Some(Rc::new(Quotient{left, right}));
+    }
+
+    fn visit_power(&mut self, power: &Power) {
+        power.base().accept(self);
+        let base = self.result.clone().unwrap();
+    
+        power.exponent().accept(self);
+        let exponent = self.result.clone().unwrap();
+        self.result = Some(Rc::new(Power{base, exponent}));
+    }
+}
+
+// Rebuilds the tree with every subtree structurally equal to the target replaced
+pub struct Replace<'a> {
+    result: Option<Rc<dyn Expression>>,
+    target: &'a dyn Expression,
+    replacement: &'a dyn Expression,
+}
+
+impl<'a> Replace<'a> {
+    pub fn transform(expression: &dyn Expression, target: &'a dyn Expression, replacement: &'a dyn Expression) -> Rc<dyn Expression> {
+        let mut replace = Replace { result: None, target, replacement };
+        expression.accept(&mut replace);
+        replace.result.unwrap()
+    }
+}
+
+impl Replace<'_> {
+    fn replace(&mut self, expression: &dyn Expression) -> Rc<dyn Expression> {
+        expression.accept(self);
+        self.result.take().unwrap()
+    }
+
+    fn matches(&mut self, expression: &dyn Expression) -> bool {
+        if structurally_equal(expression, self.target) {
+            self.result = Some(self.replacement.rc_clone());
+            return true;
+        }
+        false
+    }
+}
+
+impl<'a> Visitor for Replace<'a> {
+    fn visit_const(&mut self, cst: &Const) {
+        if !self.matches(cst) {
+            self.result = Some(Rc::new(cst.clone()));
+        }
+    }
+
+    fn visit_var(&mut self, var: &Variable) {
+        if !self.matches(var) {
+            self.result = Some(Rc::new(var.clone()));
+        }
+    }
+
+    fn visit_sum(&mut self, sum: &Sum) {
+        if !self.matches(sum) {
+            let left = self.replace(sum.left());
+            let right = self.replace(sum.right());
+            self.result = Some(Rc::new(Sum{left, right}));
+        }
+    }
+
+    fn visit_difference(&mut self, difference: &Difference) {
+        if !self.matches(difference) {
+            let left = self.replace(difference.left());
+            let right = self.replace(difference.right());
+            self.result = Some(Rc::new(Difference{left, right}));
+        }
+    }
+
+    fn visit_product(&mut self, product: &Product) {
+        if !self.matches(product) {
+            let left = self.replace(product.left());
+            let right = self.replace(product.right());
+            self.result = Some(Rc::new(Product{left, right}));
+        }
+    }
+
+    fn visit_quotient(&mut self, quotient: &Quotient) {
+        if !self.matches(quotient) {
+            let left = self.replace(quotient.left());
+            let right = self.replace(quotient.right());
+            self.result = Some(Rc::new(Quotient{left, right}));
+        }
+    }
+
+    fn visit_power(&mut self, power: &Power) {
+        if !self.matches(power) {
+            let base = self.replace(power.base());
+            let exponent = self.replace(power.exponent());
+            self.result = Some(Rc::new(Power{base, exponent}));
+        }
+    }
+}
+
+pub struct Derivative<'a> {
+    result: Option<Rc<dyn Expression>>,
+    variable: &'a str,
+}
+
+impl<'a> Derivative<'a> {
+    pub fn transform(expression: &dyn Expression, variable: &'a str) -> Rc<dyn Expression> {
+        let mut derivative = Derivative { result: None, variable };
+        expression.accept(&mut derivative);
+        derivative.result.unwrap()
+    }
+}
+
+impl Derivative<'_> {
+    fn derive(&mut self, expression: &dyn Expression) -> Rc<dyn Expression> {
+        expression.accept(self);
+        self.result.take().unwrap()
+    }
+}
+
+impl<'a> Visitor for Derivative<'a> {
+    fn visit_const(&mut self, _cst: &Const) {
+        self.result = Some(Rc::new(Const::new(0)));
+    }
+
+    fn visit_var(&mut self, var: &Variable) {
+        let value = if var.name() == self.variable { 1 } else { 0 };
+        self.result = Some(Rc::new(Const::new(value)));
+    }
+
+    fn visit_sum(&mut self, sum: &Sum) {
+        let left = self.derive(sum.left());
+        let right = self.derive(sum.right());
+        self.result = Some(Rc::new(Sum{left, right}));
+    }
+
+    fn visit_difference(&mut self, difference: &Difference) {
+        let left = self.derive(difference.left());
+        let right = self.derive(difference.right());
+        self.result = Some(Rc::new(Difference{left, right}));
+    }
+
+    // (uv)' = u'v + uv'
+    fn visit_product(&mut self, product: &Product) {
+        let left = Rc::new(Product{left: self.derive(product.left()), right: product.right.clone()});
+        let right = Rc::new(Product{left: product.left.clone(), right: self.derive(product.right())});
+        self.result = Some(Rc::new(Sum{left, right}));
+    }
+
+    // (u/v)' = (u'v - uv') / v^2
+    fn visit_quotient(&mut self, quotient: &Quotient) {
+        let left = Rc::new(Product{left: self.derive(quotient.left()), right: quotient.right.clone()});
+        let right = Rc::new(Product{left: quotient.left.clone(), right: self.derive(quotient.right())});
+        let denominator = Rc::new(Product{left: quotient.right.clone(), right: quotient.right.clone()});
+        self.result = Some(Rc::new(Quotient{left: Rc::new(Difference{left, right}), right: denominator}));
+    }
+
+    // (u^n)' = n * u^(n - 1) * u', the exponent is expected not to depend on the variable
+    fn visit_power(&mut self, power: &Power) {
+        let exponent = Rc::new(Difference{left: power.exponent.clone(), right: Rc::new(Const::new(1))});
+        let power_rule = Rc::new(Product{left: power.exponent.clone(), right: Rc::new(Power{base: power.base.clone(), exponent})});
+        self.result = Some(Rc::new(Product{left: power_rule, right: self.derive(power.base())}));
+    }
+}
+
+pub struct Simplify {
+    result: Option<Rc<dyn Expression>>,
+}
+
+impl Simplify {
+    pub fn transform(expression: &dyn Expression) -> Rc<dyn Expression> {
+        let mut simplify = Simplify { result: None };
+        expression.accept(&mut simplify);
+        simplify.result.unwrap()
+    }
+
+    fn simplify(&mut self, expression: &dyn Expression) -> Rc<dyn Expression> {
+        expression.accept(self);
+        self.result.take().unwrap()
+    }
+
+    // Value of a constant, None for anything else. The operands are simplified first,
+    // so an operand without any variables has already been folded to a constant
+    fn constant(expression: &Rc<dyn Expression>) -> Option<i32> {
+        match Decompose::transform(expression.as_ref()) {
+            (Node::Const(value), _) => Some(value),
+            _ => None,
+        }
+    }
+
+    // Collapses an operation on two constants to a single constant when it can be evaluated
+    fn fold(expression: Rc<dyn Expression>) -> Rc<dyn Expression> {
+        let (operation, operands) = Decompose::transform(expression.as_ref());
+        let values: Option<Vec<i32>> = operands.iter().map(Self::constant).collect();
+        match values.as_deref().map(|values| Evaluate::apply(&operation, values[0], values[1])) {
+            Some(Ok(value)) => Rc::new(Const::new(value)),
+            _ => expression,
+        }
+    }
+}
+
+impl Visitor for Simplify {
+    fn visit_const(&mut self, cst: &Const) {
+        self.result = Some(Rc::new(cst.clone()));
+    }
+
+    fn visit_var(&mut self, var: &Variable) {
+        self.result = Some(Rc::new(var.clone()));
+    }
+
+    fn visit_sum(&mut self, sum: &Sum) {
+        let left = self.simplify(sum.left());
+        let right = self.simplify(sum.right());
+        self.result = Some(match (Self::constant(&left), Self::constant(&right)) {
+            (Some(0), _) => right,
+            (_, Some(0)) => left,
+            _ => Self::fold(Rc::new(Sum{left, right})),
+        });
+    }
+
+    fn visit_difference(&mut self, difference: &Difference) {
+        let left = self.simplify(difference.left());
+        let right = self.simplify(difference.right());
+        self.result = Some(match Self::constant(&right) {
+            Some(0) => left,
+            _ => Self::fold(Rc::new(Difference{left, right})),
+        });
+    }
+
+    fn visit_product(&mut self, product: &Product) {
+        let left = self.simplify(product.left());
+        let right = self.simplify(product.right());
+        self.result = Some(match (Self::constant(&left), Self::constant(&right)) {
+            (Some(0), _) | (_, Some(0)) => Rc::new(Const::new(0)),
+            (Some(1), _) => right,
+            (_, Some(1)) => left,
+            _ => Self::fold(Rc::new(Product{left, right})),
+        });
+    }
+
+    fn visit_quotient(&mut self, quotient: &Quotient) {
+        let left = self.simplify(quotient.left());
+        let right = self.simplify(quotient.right());
+        self.result = Some(match Self::constant(&right) {
+            Some(1) => left,
+            _ => Self::fold(Rc::new(Quotient{left, right})),
+        });
+    }
+
+    fn visit_power(&mut self, power: &Power) {
+        let base = self.simplify(power.base());
+        let exponent = self.simplify(power.exponent());
+        self.result = Some(match Self::constant(&exponent) {
+            Some(0) => Rc::new(Const::new(1)),
+            Some(1) => base,
+            _ => Self::fold(Rc::new(Power{base, exponent})),
+        });
+    }
+}
+
+// Evaluates what the known values allow, keeping the unknown variables symbolic
+pub struct PartialEvaluate;
+
+impl PartialEvaluate {
+    pub fn transform(expression: &dyn Expression, values: &HashMap<String, i32>) -> Rc<dyn Expression> {
+        let constants: HashMap<&String, Const> = values.iter()
+            .map(|(name, &value)| (name, Const::new(value)))
+            .collect();
+        let substitutions: HashMap<String, &dyn Expression> = constants.iter()
+            .map(|(&name, cst)| (name.clone(), cst.as_ref()))
+            .collect();
+        let substituted = Substitute::transform(expression, &substitutions);
+        Simplify::transform(substituted.as_ref())
+    }
+}
+
+pub struct NodeCount {
+    result: usize,
+}
+
+impl NodeCount {
+    pub fn transform(expression: &dyn Expression) -> usize {
+        let mut visitor = NodeCount { result: 0 };
+        expression.accept(&mut visitor);
+        visitor.result
+    }
+
+    fn binary(&mut self, left: &dyn Expression, right: &dyn Expression) {
+        self.result += 1;
+        left.accept(self);
+        right.accept(self);
+    }
+}
+
+impl Visitor for NodeCount {
+    fn visit_const(&mut self, _cst: &Const) {
+        self.result += 1;
+    }
+
+    fn visit_var(&mut self, _var: &Variable) {
+        self.result += 1;
+    }
+
+    fn visit_sum(&mut self, sum: &Sum) {
+        self.binary(sum.left(), sum.right());
+    }
+
+    fn visit_difference(&mut self, difference: &Difference) {
+        self.binary(difference.left(), difference.right());
+    }
+
+    fn visit_product(&mut self, product: &Product) {
+        self.binary(product.left(), product.right());
+    }
+
+    fn visit_quotient(&mut self, quotient: &Quotient) {
+        self.binary(quotient.left(), quotient.right());
+    }
+
+    fn visit_power(&mut self, power: &Power) {
+        self.binary(power.base(), power.exponent());
+    }
+}
+
+pub struct Depth {
+    result: usize,
+}
+
+impl Depth {
+    pub fn transform(expression: &dyn Expression) -> usize {
+        let mut visitor = Depth { result: 0 };
+        expression.accept(&mut visitor);
+        visitor.result
+    }
+
+    fn binary(&mut self, left: &dyn Expression, right: &dyn Expression) {
+        left.accept(self);
+        let left = self.result;
+        right.accept(self);
+        self.result = left.max(self.result) + 1;
+    }
+}
+
+impl Visitor for Depth {
+    fn visit_const(&mut self, _cst: &Const) {
+        self.result = 1;
+    }
+
+    fn visit_var(&mut self, _var: &Variable) {
+        self.result = 1;
+    }
+
+    fn visit_sum(&mut self, sum: &Sum) {
+        self.binary(sum.left(), sum.right());
+    }
+
+    fn visit_difference(&mut self, difference: &Difference) {
+        self.binary(difference.left(), difference.right());
+    }
+
+    fn visit_product(&mut self, product: &Product) {
+        self.binary(product.left(), product.right());
+    }
+
+    fn visit_quotient(&mut self, quotient: &Quotient) {
+        self.binary(quotient.left(), quotient.right());
+    }
+
+    fn visit_power(&mut self, power: &Power) {
+        self.binary(power.base(), power.exponent());
+    }
+}
+
+#[derive(PartialEq)]
+enum Node {
+    Const(i32),
+    Variable(String),
+    Sum,
+    Difference,
+    Product,
+    Quotient,
+    Power,
+}
+
+// Splits an expression into its top node and its operands
+struct Decompose {
+    node: Option<Node>,
+    operands: Vec<Rc<dyn Expression>>,
+}
+
+impl Decompose {
+    fn transform(expression: &dyn Expression) -> (Node, Vec<Rc<dyn Expression>>) {
+        let mut visitor = Decompose { node: None, operands: Vec::new() };
+        expression.accept(&mut visitor);
+        (visitor.node.unwrap(), visitor.operands)
+    }
+
+    fn binary(&mut self, node: Node, left: &Rc<dyn Expression>, right: &Rc<dyn Expression>) {
+        self.node = Some(node);
+        self.operands = vec![left.clone(), right.clone()];
+    }
+}
+
+impl Visitor for Decompose {
+    fn visit_const(&mut self, cst: &Const) {
+        self.node = Some(Node::Const(cst.value()));
+    }
+
+    fn visit_var(&mut self, var: &Variable) {
+        self.node = Some(Node::Variable(var.name().to_string()));
+    }
+
+    fn visit_sum(&mut self, sum: &Sum) {
+        self.binary(Node::Sum, &sum.left, &sum.right);
+    }
+
+    fn visit_difference(&mut self, difference: &Difference) {
+        self.binary(Node::Difference, &difference.left, &difference.right);
+    }
+
+    fn visit_product(&mut self, product: &Product) {
+        self.binary(Node::Product, &product.left, &product.right);
+    }
+
+    fn visit_quotient(&mut self, quotient: &Quotient) {
+        self.binary(Node::Quotient, &quotient.left, &quotient.right);
+    }
+
+    fn visit_power(&mut self, power: &Power) {
+        self.binary(Node::Power, &power.base, &power.exponent);
+    }
+}
+
+enum Task {
+    Visit(Rc<dyn Expression>),
+    Apply(Node),
+}
+
+// Hands the nodes to `visit` in postfix order, using an explicit stack so that deep trees
+// do not overflow the native one
+fn walk_postfix<E>(expression: &dyn Expression, mut visit: impl FnMut(Node) -> Result<(), E>) -> Result<(), E> {
+    let mut tasks = vec![Task::Visit(expression.rc_clone())];
+    while let Some(task) = tasks.pop() {
+        match task {
+            Task::Visit(expression) => {
+                let (node, operands) = Decompose::transform(expression.as_ref());
+                if operands.is_empty() {
+                    visit(node)?;
+                } else {
+                    tasks.push(Task::Apply(node));
+                    tasks.extend(operands.into_iter().rev().map(Task::Visit));
+                }
+            }
+            Task::Apply(node) => visit(node)?,
+        }
+    }
+    Ok(())
+}
+
+// Dropping a deep tree would recurse through all of its nodes, so the operands this node
+// owns alone are taken apart here with an explicit stack instead
+fn drop_operands(operands: [&mut Rc<dyn Expression>; 2]) {
+    let mut pending = Vec::new();
+    for operand in operands {
+        if Rc::strong_count(operand) == 1 {
+            pending.push(std::mem::replace(operand, Rc::new(Const::new(0))));
+        }
+    }
+    while let Some(expression) = pending.pop() {
+        if Rc::strong_count(&expression) == 1 {
+            pending.extend(Decompose::transform(expression.as_ref()).1);
+        }
+    }
+}
+
+// Expressions are equal when they are the same tree, so Sum(a, b) and Sum(b, a) differ
+pub fn structurally_equal(a: &dyn Expression, b: &dyn Expression) -> bool {
+    let (a_node, a_operands) = Decompose::transform(a);
+    let (b_node, b_operands) = Decompose::transform(b);
+    a_node == b_node && a_operands.iter()
+        .zip(b_operands.iter())
+        .all(|(a, b)| structurally_equal(a.as_ref(), b.as_ref()))
+}
+
+fn main() {
+    let p = expr!((a + (27 + a + a)) * 27);
+    println!("{}", PostfixConvertor::transform(p.as_ref()));
+    println!("{}", InfixConvertor::transform(p.as_ref()));
+    let values = HashMap::from([("a".to_string(), 42)]);
+    match Evaluate::transform(p.as_ref(), &values) {
+        Ok(value) => println!("{}", value),
+        Err(error) => println!("{}", error),
+    }
+
+    let mut values: HashMap<_, &dyn Expression> = HashMap::new();
+    values.insert("a".to_string(), p.as_ref());
+    let exp = Substitute::transform(p.as_ref(), &values);
+    println!("{}", PostfixConvertor::transform(exp.as_ref()));
+
+    let derivative = Derivative::transform(p.as_ref(), "a");
+    println!("{}", InfixConvertor::transform(Simplify::transform(derivative.as_ref()).as_ref()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn x() -> Variable { Variable::new("x".to_string()) }
+    fn y() -> Variable { Variable::new("y".to_string()) }
+
+    // (a + (27 + a + a)) * 27
+    fn sample() -> Product {
+        let c = Const::new(27);
+        let v = Variable::new("a".to_string());
+        let s = Sum::new(c.clone(), v.clone());
+        let s1 = Sum::new(s, v.clone());
+        Product::new(Sum::new(v, s1), c)
+    }
+
+    #[test]
+    fn converts_to_postfix_and_evaluates() {
+        let p = sample();
+        assert_eq!(PostfixConvertor::transform(p.as_ref()), "a 27 a + a + + 27 *");
+        let values = HashMap::from([("a".to_string(), 42)]);
+        assert_eq!(Evaluate::transform(p.as_ref(), &values), Ok((42 + 27 + 42 + 42) * 27));
+    }
+
+    #[test]
+    fn substitutes() {
+        let p = sample();
+        let values: HashMap<_, &dyn Expression> = HashMap::from([("a".to_string(), p.as_ref())]);
+        let substituted = Substitute::transform(p.as_ref(), &values);
+        assert_eq!(NodeCount::transform(substituted.as_ref()), 9 + 3 * 8);
+    }
+
+    #[test]
+    fn subtracts() {
+        let difference = Difference::new(Const::new(5), Const::new(3));
+        assert_eq!(Evaluate::transform(difference.as_ref(), &HashMap::new()), Ok(2));
+        assert_eq!(PostfixConvertor::transform(difference.as_ref()), "5 3 -");
+        let difference = Difference::new(Variable::new("a".to_string()), Product::new(Const::new(2), Variable::new("b".to_string())));
+        let values = HashMap::from([("a".to_string(), 1), ("b".to_string(), 4)]);
+        assert_eq!(Evaluate::transform(difference.as_ref(), &values), Ok(-7));
+        let replacement = Const::new(10);
+        let substitutions: HashMap<_, &dyn Expression> = HashMap::from([("b".to_string(), replacement.as_ref())]);
+        let substituted = Substitute::transform(difference.as_ref(), &substitutions);
+        assert_eq!(PostfixConvertor::transform(substituted.as_ref()), "a 2 10 * -");
+    }
+
+    #[test]
+    fn divides() {
+        let quotient = Quotient::new(Const::new(6), Const::new(2));
+        assert_eq!(Evaluate::transform(quotient.as_ref(), &HashMap::new()), Ok(3));
+        assert_eq!(PostfixConvertor::transform(quotient.as_ref()), "6 2 /");
+        let quotient = Quotient::new(Const::new(6), Difference::new(Variable::new("a".to_string()), Const::new(2)));
+        let values = HashMap::from([("a".to_string(), 2)]);
+        assert_eq!(Evaluate::transform(quotient.as_ref(), &values), Err(EvalError::DivisionByZero));
+    }
+
+    #[test]
+    fn raises_to_a_power() {
+        let power = Power::new(Const::new(2), Const::new(10));
+        assert_eq!(Evaluate::transform(power.as_ref(), &HashMap::new()), Ok(1024));
+        assert_eq!(PostfixConvertor::transform(power.as_ref()), "2 10 ^");
+        let power = Power::new(Const::new(2), Variable::new("n".to_string()));
+        let values = HashMap::from([("n".to_string(), -1)]);
+        assert_eq!(Evaluate::transform(power.as_ref(), &values), Err(EvalError::NegativeExponent(-1)));
+        let replacement = Const::new(10);
+        let substitutions: HashMap<_, &dyn Expression> = HashMap::from([("n".to_string(), replacement.as_ref())]);
+        let substituted = Substitute::transform(power.as_ref(), &substitutions);
+        assert_eq!(Evaluate::transform(substituted.as_ref(), &HashMap::new()), Ok(1024));
+    }
+
+    #[test]
+    fn derives() {
+        let square = Product::new(x(), x());
+        let derivative = Derivative::transform(square.as_ref(), "x");
+        let values = HashMap::from([("x".to_string(), 3)]);
+        assert_eq!(Evaluate::transform(derivative.as_ref(), &values), Ok(6));
+        assert_eq!(PostfixConvertor::transform(derivative.as_ref()), "1 x * x 1 * +");
+        let cube = Sum::new(Power::new(x(), Const::new(3)), y());
+        let derivative = Derivative::transform(cube.as_ref(), "x");
+        assert_eq!(Evaluate::transform(derivative.as_ref(), &values), Ok(27));
+    }
+
+    #[test]
+    fn simplifies() {
+        let sum = Sum::new(x(), Const::new(0));
+        assert_eq!(PostfixConvertor::transform(Simplify::transform(sum.as_ref()).as_ref()), "x");
+        let product = Product::new(Const::new(2), Const::new(3));
+        assert_eq!(PostfixConvertor::transform(Simplify::transform(product.as_ref()).as_ref()), "6");
+        let derivative = Derivative::transform(Product::new(x(), x()).as_ref(), "x");
+        assert_eq!(PostfixConvertor::transform(Simplify::transform(derivative.as_ref()).as_ref()), "x x +");
+        let cube = Sum::new(Power::new(x(), Const::new(3)), y());
+        let derivative = Derivative::transform(cube.as_ref(), "x");
+        assert_eq!(PostfixConvertor::transform(Simplify::transform(derivative.as_ref()).as_ref()), "3 x 2 ^ *");
+        let quotient = Quotient::new(x(), Difference::new(Const::new(1), Const::new(1)));
+        assert_eq!(PostfixConvertor::transform(Simplify::transform(quotient.as_ref()).as_ref()), "x 0 /");
+        let product = Product::new(Const::new(100_000), Const::new(100_000));
+        assert_eq!(PostfixConvertor::transform(Simplify::transform(product.as_ref()).as_ref()), "100000 100000 *");
+        let three = Sum::new(Const::new(1), Const::new(2));
+        let square = Power::new(Const::new(3), Difference::new(Const::new(4), Const::new(2)));
+        let nested = Sum::new(Product::new(three, Sum::new(Const::new(3), x())), square);
+        assert_eq!(InfixConvertor::transform(Simplify::transform(nested.as_ref()).as_ref()), "3 * (3 + x) + 9");
+    }
+
+    #[test]
+    fn converts_to_infix_with_minimal_parentheses() {
+        let a = || Variable::new("a".to_string());
+        let b = || Variable::new("b".to_string());
+        let c = || Variable::new("c".to_string());
+        assert_eq!(InfixConvertor::transform(Product::new(Sum::new(a(), b()), c()).as_ref()), "(a + b) * c");
+        assert_eq!(InfixConvertor::transform(Sum::new(Product::new(a(), b()), c()).as_ref()), "a * b + c");
+        assert_eq!(InfixConvertor::transform(Sum::new(a(), Sum::new(b(), c())).as_ref()), "a + b + c");
+        assert_eq!(InfixConvertor::transform(Difference::new(a(), Sum::new(b(), c())).as_ref()), "a - (b + c)");
+        assert_eq!(InfixConvertor::transform(Quotient::new(a(), Product::new(b(), Const::new(2))).as_ref()), "a / (b * 2)");
+        assert_eq!(InfixConvertor::transform(Power::new(Power::new(a(), b()), c()).as_ref()), "(a ^ b) ^ c");
+        assert_eq!(InfixConvertor::transform(sample().as_ref()), "(a + 27 + a + a) * 27");
+    }
+
+    #[test]
+    fn counts_nodes_and_depth() {
+        assert_eq!(NodeCount::transform(Const::new(1).as_ref()), 1);
+        assert_eq!(Depth::transform(Const::new(1).as_ref()), 1);
+        let tree = Sum::new(Const::new(1), Product::new(Const::new(2), Const::new(3)));
+        assert_eq!(NodeCount::transform(tree.as_ref()), 5);
+        assert_eq!(Depth::transform(tree.as_ref()), 3);
+        assert_eq!(NodeCount::transform(sample().as_ref()), 9);
+        assert_eq!(Depth::transform(sample().as_ref()), 5);
+    }
+
+    #[test]
+    fn evaluates_partially() {
+        let sum = Sum::new(x(), Const::new(5));
+        let partial = PartialEvaluate::transform(sum.as_ref(), &HashMap::new());
+        assert_eq!(PostfixConvertor::transform(partial.as_ref()), "x 5 +");
+        let partial = PartialEvaluate::transform(sum.as_ref(), &HashMap::from([("x".to_string(), 2)]));
+        assert_eq!(PostfixConvertor::transform(partial.as_ref()), "7");
+        let tree = Sum::new(Const::new(1), Product::new(Const::new(2), Const::new(3)));
+        let partial = PartialEvaluate::transform(tree.as_ref(), &HashMap::new());
+        assert_eq!(PostfixConvertor::transform(partial.as_ref()), "7");
+        let difference = Difference::new(Variable::new("a".to_string()), Product::new(Const::new(2), Variable::new("b".to_string())));
+        let partial = PartialEvaluate::transform(difference.as_ref(), &HashMap::from([("b".to_string(), 0)]));
+        assert_eq!(PostfixConvertor::transform(partial.as_ref()), "a");
+    }
+
+    #[test]
+    fn reports_evaluation_errors() {
+        let error = Evaluate::transform(Sum::new(x(), Const::new(5)).as_ref(), &HashMap::new()).unwrap_err();
+        assert_eq!(error, EvalError::MissingVariable("x".to_string()));
+        assert_eq!(error.to_string(), "Missing variable x");
+
+        let product = Product::new(Const::new(100_000), Const::new(100_000));
+        assert_eq!(Evaluate::transform(product.as_ref(), &HashMap::new()), Err(EvalError::Overflow));
+        let sum = Sum::new(Const::new(i32::MAX), Const::new(1));
+        assert_eq!(Evaluate::transform(sum.as_ref(), &HashMap::new()), Err(EvalError::Overflow));
+        let quotient = Quotient::new(Const::new(i32::MIN), Const::new(-1));
+        assert_eq!(Evaluate::transform(quotient.as_ref(), &HashMap::new()), Err(EvalError::Overflow));
+    }
+
+    #[test]
+    fn compares_structurally() {
+        let p = sample();
+        assert!(structurally_equal(p.as_ref(), p.rc_clone().as_ref()));
+        assert!(structurally_equal(Sum::new(x(), y()).as_ref(), Sum::new(x(), y()).as_ref()));
+        assert!(!structurally_equal(Sum::new(x(), y()).as_ref(), Sum::new(y(), x()).as_ref()));
+        assert!(!structurally_equal(Sum::new(x(), y()).as_ref(), Product::new(x(), y()).as_ref()));
+        assert!(!structurally_equal(Const::new(1).as_ref(), Const::new(2).as_ref()));
+    }
+
+    #[test]
+    fn folds() {
+        struct Counter;
+        impl Folder for Counter {
+            type Output = usize;
+            fn fold_const(&mut self, _cst: &Const) -> usize { 1 }
+            fn fold_var(&mut self, _var: &Variable) -> usize { 1 }
+            fn fold_sum(&mut self, sum: &Sum) -> usize { 1 + sum.left().accept_fold(self) + sum.right().accept_fold(self) }
+            fn fold_difference(&mut self, difference: &Difference) -> usize { 1 + difference.left().accept_fold(self) + difference.right().accept_fold(self) }
+            fn fold_product(&mut self, product: &Product) -> usize { 1 + product.left().accept_fold(self) + product.right().accept_fold(self) }
+            fn fold_quotient(&mut self, quotient: &Quotient) -> usize { 1 + quotient.left().accept_fold(self) + quotient.right().accept_fold(self) }
+            fn fold_power(&mut self, power: &Power) -> usize { 1 + power.base().accept_fold(self) + power.exponent().accept_fold(self) }
+        }
+        let tree = Sum::new(Const::new(1), Product::new(Const::new(2), Const::new(3)));
+        assert_eq!(tree.as_ref().accept_fold(&mut Counter), 5);
+        assert_eq!(sample().as_ref().accept_fold(&mut Counter), NodeCount::transform(sample().as_ref()));
+        let values = HashMap::from([("a".to_string(), 42)]);
+        assert_eq!(sample().as_ref().accept_fold(&mut Evaluate::new(&values)), Evaluate::transform(sample().as_ref(), &values));
+        assert_eq!(x().as_ref().accept_fold(&mut Evaluate::new(&values)), Err(EvalError::MissingVariable("x".to_string())));
+    }
+
+    #[test]
+    fn handles_deep_trees() {
+        let mut deep: Rc<dyn Expression> = Rc::new(Const::new(1));
+        for _ in 1..100_000 {
+            deep = Rc::new(Sum{left: deep, right: Rc::new(Const::new(1))});
+        }
+        assert_eq!(Evaluate::transform(deep.as_ref(), &HashMap::new()), Ok(100_000));
+        assert_eq!(deep.as_ref().accept_fold(&mut Evaluate::new(&HashMap::new())), Ok(100_000));
+        assert_eq!(PostfixConvertor::transform(deep.as_ref()).len(), 4 * 100_000 - 3);
+    }
+
+    #[test]
+    fn parses_postfix() {
+        let p = sample();
+        let values = HashMap::from([("a".to_string(), 42)]);
+        let parsed = parse_postfix(&PostfixConvertor::transform(p.as_ref())).unwrap();
+        assert_eq!(Evaluate::transform(parsed.as_ref(), &values), Evaluate::transform(p.as_ref(), &values));
+        assert!(structurally_equal(parsed.as_ref(), p.as_ref()));
+        let parsed = parse_postfix("x -3 - 2 ^ y /").unwrap();
+        assert_eq!(InfixConvertor::transform(parsed.as_ref()), "(x - -3) ^ 2 / y");
+        assert_eq!(parse_postfix("1 +").err(), Some("Missing operand for '+'".to_string()));
+        assert_eq!(parse_postfix("1 2 3 *").err(), Some("Missing operators for 1 operands".to_string()));
+        assert_eq!(parse_postfix("  ").err(), Some("Empty expression".to_string()));
+    }
+
+    #[test]
+    fn builds_with_the_macro() {
+        let built = expr!((x + 2) * (y + 3));
+        let by_hand = Product::new(Sum::new(x(), Const::new(2)), Sum::new(y(), Const::new(3)));
+        let values = HashMap::from([("x".to_string(), 1), ("y".to_string(), 2)]);
+        assert_eq!(Evaluate::transform(built.as_ref(), &values), Ok(15));
+        assert_eq!(Evaluate::transform(built.as_ref(), &values), Evaluate::transform(by_hand.as_ref(), &values));
+        assert!(structurally_equal(built.as_ref(), by_hand.as_ref()));
+        assert_eq!(InfixConvertor::transform(expr!(2 * x * x + y + 1).as_ref()), "2 * x * x + y + 1");
+        assert_eq!(PostfixConvertor::transform(expr!(a * (b + c)).as_ref()), "a b c + *");
+        assert_eq!(PostfixConvertor::transform(expr!(((7))).as_ref()), "7");
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let sum = Sum::new(x(), Const::new(1));
+        let json = JsonConvertor::transform(sum.as_ref());
+        assert_eq!(json, r#"{"op":"sum","left":{"op":"var","name":"x"},"right":{"op":"const","value":1}}"#);
+        assert!(structurally_equal(parse_json(&json).unwrap().as_ref(), sum.as_ref()));
+        let tricky = Power::new(Variable::new("a \"quoted\" \\ name".to_string()), Quotient::new(Const::new(-4), x()));
+        let parsed = parse_json(&JsonConvertor::transform(tricky.as_ref())).unwrap();
+        assert!(structurally_equal(parsed.as_ref(), tricky.as_ref()));
+        let controls = Variable::new("a\r\u{0}b\u{1f}\n\tc".to_string());
+        let json = JsonConvertor::transform(&controls);
+        assert_eq!(json, r#"{"op":"var","name":"a\u000d\u0000b\u001f\n\tc"}"#);
+        assert!(structurally_equal(parse_json(&json).unwrap().as_ref(), &controls));
+        assert_eq!(parse_json(r#"{"op":"var","name":"\u00e9\r"}"#).map(|parsed| InfixConvertor::transform(parsed.as_ref())), Ok("é\r".to_string()));
+        assert_eq!(parse_json(r#"{"op":"var","name":"\u00zz"}"#).err(), Some("Invalid unicode escape \"\\u00zz\"".to_string()));
+        let p = sample();
+        assert!(structurally_equal(parse_json(&JsonConvertor::transform(p.as_ref())).unwrap().as_ref(), p.as_ref()));
+        assert_eq!(parse_json(r#"{ "op" : "const" }"#).err(), Some("Missing field \"value\"".to_string()));
+        assert_eq!(parse_json(r#"{"op":"modulo"}"#).err(), Some("Unknown operation \"modulo\"".to_string()));
+    }
+
+    #[test]
+    fn replaces_subexpressions() {
+        let target = expr!(x * y);
+        let larger = expr!(x * y + 2 * (x * y + x) + y * x);
+        let replaced = Replace::transform(larger.as_ref(), target.as_ref(), Const::new(0).as_ref());
+        assert_eq!(InfixConvertor::transform(replaced.as_ref()), "0 + 2 * (0 + x) + y * x");
+        let replaced = Replace::transform(larger.as_ref(), x().as_ref(), expr!(z + 1).as_ref());
+        assert_eq!(InfixConvertor::transform(replaced.as_ref()), "(z + 1) * y + 2 * ((z + 1) * y + z + 1) + y * (z + 1)");
+    }
 }
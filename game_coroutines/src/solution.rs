@@ -1,15 +1,73 @@
-use tokio::sync::Mutex;
+// The demo in main uses only a part of the library, the rest is covered by the tests
+#![cfg_attr(not(test), allow(dead_code))]
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, Mutex};
 use std::sync::Arc;
-use std::collections::VecDeque;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Key {
     Left,
     Right,
     Up,
     Down, 
+    UpLeft,
+    UpRight,
+    DownLeft,
+    DownRight,
     Quit  
 }
 
+/// Reads vim-like `hjkl` or `wasd` movement keys and `q` to quit.
+impl TryFrom<char> for Key {
+    type Error = String;
+
+    fn try_from(c: char) -> Result<Self, Self::Error> {
+        match c {
+            'h' | 'a' => Ok(Key::Left),
+            'j' | 's' => Ok(Key::Down),
+            'k' | 'w' => Ok(Key::Up),
+            'l' | 'd' => Ok(Key::Right),
+            'q' => Ok(Key::Quit),
+            _ => Err(format!("Unknown key '{}'", c)),
+        }
+    }
+}
+
+impl Key {
+    // How the key moves the token along both axes, the y axis points down
+    fn delta(&self) -> Option<(i64, i64)> {
+        match self {
+            Key::Left => Some((-1, 0)),
+            Key::Right => Some((1, 0)),
+            Key::Up => Some((0, -1)),
+            Key::Down => Some((0, 1)),
+            Key::UpLeft => Some((-1, -1)),
+            Key::UpRight => Some((1, -1)),
+            Key::DownLeft => Some((-1, 1)),
+            Key::DownRight => Some((1, 1)),
+            Key::Quit => None,
+        }
+    }
+
+    // The key moving the token the same way as the given offsets, only their signs matter
+    fn towards(dx: i64, dy: i64) -> Option<Key> {
+        match (dx.signum(), dy.signum()) {
+            (-1, 0) => Some(Key::Left),
+            (1, 0) => Some(Key::Right),
+            (0, -1) => Some(Key::Up),
+            (0, 1) => Some(Key::Down),
+            (-1, -1) => Some(Key::UpLeft),
+            (1, -1) => Some(Key::UpRight),
+            (-1, 1) => Some(Key::DownLeft),
+            (1, 1) => Some(Key::DownRight),
+            _ => None,
+        }
+    }
+}
+
 pub struct Keyboard {
     game: Arc<Mutex<Game>>,
 }
@@ -19,29 +77,105 @@ impl Keyboard {
         let mut game = self.game.lock().await;
         game.process_key(key).await;
     }
+
+    /// Presses the keys one after another, holding the game for the whole batch.
+    pub async fn push_all(&mut self, keys: impl IntoIterator<Item = Key>) {
+        let mut game = self.game.lock().await;
+        for key in keys {
+            game.process_key(key).await;
+        }
+    }
+
+    /// Moves the token by an arbitrary number of cells, walking the way like a key press does,
+    /// so it stops at the edges of the board and in front of obstacles. The move is logged as a jump.
+    pub async fn move_by(&mut self, dx: i64, dy: i64) {
+        let mut game = self.game.lock().await;
+        game.move_by(dx, dy).await;
+    }
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum LogRecord {
     Started(usize, usize),
     Moved(usize, usize),
+    Jumped(usize, usize), // moved by `Keyboard::move_by`, possibly further than a single key press goes
     Stayed,
+    HitWall(Key), // the move in the direction of the key was blocked by the edge of the board
+    Undone(usize, usize), // the last move was taken back, the token is at the given cell again
+    Restored(GameSnapshot),
     Finished,
 }
 
+impl fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogRecord::Started(x, y) => write!(f, "started at ({}, {})", x, y),
+            LogRecord::Moved(x, y) => write!(f, "moved to ({}, {})", x, y),
+            LogRecord::Jumped(x, y) => write!(f, "jumped to ({}, {})", x, y),
+            LogRecord::Stayed => write!(f, "stayed"),
+            LogRecord::HitWall(key) => write!(f, "hit the wall moving {:?}", key),
+            LogRecord::Undone(x, y) => write!(f, "moved back to ({}, {})", x, y),
+            LogRecord::Restored(snapshot) => write!(f, "restored at ({}, {})", snapshot.coordinate.0, snapshot.coordinate.1),
+            LogRecord::Finished => write!(f, "finished"),
+        }
+    }
+}
+
 pub struct Logger {
     queue: Arc<Mutex<VecDeque<LogRecord>>>,
+    capacity: Option<usize>, // the oldest records are dropped once there are more of them
+    dropped: Mutex<usize>,
+    subscribers: broadcast::Sender<LogRecord>,
+}
+
+// How many records a subscriber can fall behind before it starts missing them
+const SUBSCRIPTION_CAPACITY: usize = 64;
+
+impl Default for Logger {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Logger {
     pub fn new() -> Self {
         Logger {
             queue: Arc::new(Mutex::new(VecDeque::new())),
+            capacity: None,
+            dropped: Mutex::new(0),
+            subscribers: broadcast::channel(SUBSCRIPTION_CAPACITY).0,
         }
     }
 
+    /// Creates a logger keeping only the latest `capacity` records.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Logger {
+            capacity: Some(capacity),
+            ..Self::new()
+        }
+    }
+
+    /// Starts receiving a copy of every record logged from now on, independently of the queue.
+    pub fn subscribe(&self) -> broadcast::Receiver<LogRecord> {
+        self.subscribers.subscribe()
+    }
+
     pub async fn log(&self, record: LogRecord) {
+        // Sending fails only when nobody is subscribed
+        let _ = self.subscribers.send(record.clone());
         let mut queue = self.queue.lock().await;
         queue.push_back(record);
+        if let Some(capacity) = self.capacity {
+            while queue.len() > capacity {
+                queue.pop_front();
+                *self.dropped.lock().await += 1;
+            }
+        }
+    }
+
+    /// Returns how many records were dropped because the logger was full.
+    pub async fn dropped(&self) -> usize {
+        *self.dropped.lock().await
     }
 
     pub async fn next(&self) -> Option<LogRecord> {
@@ -55,27 +189,220 @@ struct Coordinate {
     y: i64,
 }
 
+// Where a move ends, how many cells it went and whether an obstacle stopped it
+struct Walk {
+    end: (i64, i64),
+    travelled: usize,
+    blocked: bool,
+}
+
 pub struct Game {
     coordinate: Coordinate,
     board_size: (usize, usize),
+    obstacles: HashSet<(usize, usize)>,
+    wrap: bool, // leaving the board over an edge enters it again from the opposite one
+    step: usize, // how many cells a single key press moves the token by
     logger: Arc<Logger>,
     is_started: bool,
+    is_finished: bool,
+    moves_made: usize,
+    distance: usize, // cells travelled, a diagonal step counts as one cell
+    visited: HashSet<(usize, usize)>,
+    history: Vec<(usize, usize)>, // cells the token moved away from, the latest last
 }
 
-impl Game {
-    pub fn new(x: usize, y: usize) -> (Arc<Mutex<Self>>, Keyboard, Arc<Logger>)  {
+/// The state of a game that can be saved and restored later.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GameSnapshot {
+    pub coordinate: (usize, usize),
+    pub board_size: (usize, usize),
+    pub is_started: bool,
+    pub is_finished: bool,
+    pub moves_made: usize,
+    pub distance: usize,
+}
+
+pub struct GameBuilder {
+    board_size: (usize, usize),
+    start: (usize, usize),
+    obstacles: HashSet<(usize, usize)>,
+    wrap: bool,
+    step: usize,
+}
+
+impl GameBuilder {
+    pub fn start_at(mut self, x: usize, y: usize) -> Self {
+        self.start = (x, y);
+        self
+    }
+
+    /// Blocks the given cell, the token never moves onto it.
+    pub fn obstacle(mut self, x: usize, y: usize) -> Self {
+        self.obstacles.insert((x, y));
+        self
+    }
+
+    /// Makes the board wrap around, so the token moving over an edge reappears on the opposite side.
+    pub fn wrap(mut self, wrap: bool) -> Self {
+        self.wrap = wrap;
+        self
+    }
+
+    /// Moves the token by the given number of cells per key press, stopping at the edges of the board.
+    pub fn step_size(mut self, step: usize) -> Self {
+        self.step = step;
+        self
+    }
+
+    pub fn build(self) -> (Arc<Mutex<Game>>, Keyboard, Arc<Logger>) {
+        let (x, y) = self.board_size;
+        let (start_x, start_y) = self.start;
+        assert!(start_x < x && start_y < y, "Starting cell ({}, {}) is outside of the {}x{} board", start_x, start_y, x, y);
+        assert!(!self.obstacles.contains(&self.start), "Starting cell ({}, {}) is blocked", start_x, start_y);
         let logger = Arc::new(Logger::new());
 
         let game = Arc::new(Mutex::new(Game {
-            coordinate: Coordinate { x: 0, y: 0 },
-            board_size: (x, y),
+            coordinate: Coordinate { x: start_x as i64, y: start_y as i64 },
+            board_size: self.board_size,
+            obstacles: self.obstacles,
+            wrap: self.wrap,
+            step: self.step,
             logger: Arc::clone(&logger),
             is_started: false,
+            is_finished: false,
+            moves_made: 0,
+            distance: 0,
+            visited: HashSet::from([self.start]),
+            history: Vec::new(),
         }));
 
         let keyboard = Keyboard { game: Arc::clone(&game) };
         (game, keyboard, logger)
     }
+}
+
+impl Game {
+    pub fn new(x: usize, y: usize) -> (Arc<Mutex<Self>>, Keyboard, Arc<Logger>)  {
+        Self::new_at(x, y, 0, 0)
+    }
+
+    /// Creates a game with the token placed at the given cell, which must lie on the board.
+    pub fn new_at(x: usize, y: usize, start_x: usize, start_y: usize) -> (Arc<Mutex<Self>>, Keyboard, Arc<Logger>)  {
+        Self::builder(x, y).start_at(start_x, start_y).build()
+    }
+
+    pub fn builder(x: usize, y: usize) -> GameBuilder {
+        GameBuilder {
+            board_size: (x, y),
+            start: (0, 0),
+            obstacles: HashSet::new(),
+            wrap: false,
+            step: 1,
+        }
+    }
+
+    /// Rebuilds a game configured by the builder from its log, checking that every move is a single key press
+    /// and every jump could have been made by `Keyboard::move_by`. The cell the log starts at replaces the start
+    /// of the builder and a jump is counted as the direct way between the two cells.
+    pub async fn replay(records: &[LogRecord], builder: GameBuilder) -> Result<(Arc<Mutex<Self>>, Keyboard, Arc<Logger>), String> {
+        let (width, height) = builder.board_size;
+        let is_free = |x: usize, y: usize| x < width && y < height && !builder.obstacles.contains(&(x, y));
+        let start = match records.first() {
+            None => return Ok(builder.build()),
+            Some(LogRecord::Started(x, y)) if is_free(*x, *y) => (*x, *y),
+            // A game restored before its first move logs the restore first
+            Some(LogRecord::Restored(snapshot)) if is_free(snapshot.coordinate.0, snapshot.coordinate.1) => snapshot.coordinate,
+            Some(record) => return Err(format!("The log has to begin with a start on a free cell, not {:?}", record)),
+        };
+
+        let (game, keyboard, logger) = builder.start_at(start.0, start.1).build();
+        {
+            let mut state = game.lock().await;
+            for record in records {
+                if state.is_finished && !matches!(record, LogRecord::Restored(_)) {
+                    return Err(format!("The log continues with {:?} after the game has finished", record));
+                }
+                match record {
+                    LogRecord::Moved(x, y) | LogRecord::Jumped(x, y) => {
+                        let from = (state.coordinate.x, state.coordinate.y);
+                        let to = (*x as i64, *y as i64);
+                        let step = state.step as i64;
+                        let offsets: Vec<(i64, i64)> = match record {
+                            // A key press goes a single step in one of the eight directions
+                            LogRecord::Moved(..) => (-1..=1).flat_map(|dx| (-1..=1).map(move |dy| (dx * step, dy * step))).collect(),
+                            _ => vec![(to.0 - from.0, to.1 - from.1)],
+                        };
+                        let walk = offsets.into_iter()
+                            .map(|(dx, dy)| state.walk(from, dx, dy))
+                            .find(|walk| walk.travelled > 0 && walk.end == to)
+                            .ok_or_else(|| format!("Cannot move from {:?} to {:?}", from, to))?;
+                        state.history.push((from.0 as usize, from.1 as usize));
+                        state.coordinate = Coordinate { x: to.0, y: to.1 };
+                        state.moves_made += 1;
+                        state.distance += walk.travelled;
+                        state.visited.insert((*x, *y));
+                    },
+                    LogRecord::Undone(x, y) => {
+                        if state.history.pop() != Some((*x, *y)) {
+                            return Err(format!("Cannot move back from {:?} to {:?}", state.position().await, (x, y)));
+                        }
+                        state.coordinate = Coordinate { x: *x as i64, y: *y as i64 };
+                    },
+                    LogRecord::Stayed | LogRecord::HitWall(_) => {},
+                    LogRecord::Finished => state.is_finished = true,
+                    LogRecord::Restored(snapshot) => state.apply(snapshot),
+                    LogRecord::Started(x, y) if !state.is_started && state.position().await == (*x, *y) => state.is_started = true,
+                    LogRecord::Started(..) => return Err("The game can only be started once, where the token stands".to_string()),
+                }
+            }
+        }
+        Ok((game, keyboard, logger))
+    }
+
+    pub async fn position(&self) -> (usize, usize) {
+        (self.coordinate.x as usize, self.coordinate.y as usize)
+    }
+
+    /// Returns how many moves the token has made and how many cells it has travelled.
+    pub async fn stats(&self) -> (usize, usize) {
+        (self.moves_made, self.distance)
+    }
+
+    pub async fn snapshot(&self) -> GameSnapshot {
+        GameSnapshot {
+            coordinate: self.position().await,
+            board_size: self.board_size,
+            is_started: self.is_started,
+            is_finished: self.is_finished,
+            moves_made: self.moves_made,
+            distance: self.distance,
+        }
+    }
+
+    /// Puts the game back to the snapshot, the moves made before restoring can no longer be undone
+    /// and the trail starts over at the restored cell. The restore is logged, so a replay gets there too.
+    pub async fn restore(&mut self, snapshot: GameSnapshot) {
+        self.apply(&snapshot);
+        self.logger.log(LogRecord::Restored(snapshot)).await;
+    }
+
+    fn apply(&mut self, snapshot: &GameSnapshot) {
+        self.coordinate = Coordinate { x: snapshot.coordinate.0 as i64, y: snapshot.coordinate.1 as i64 };
+        self.board_size = snapshot.board_size;
+        self.is_started = snapshot.is_started;
+        self.is_finished = snapshot.is_finished;
+        self.moves_made = snapshot.moves_made;
+        self.distance = snapshot.distance;
+        self.history.clear();
+        self.visited = HashSet::from([snapshot.coordinate]);
+    }
+
+    /// Lists every cell the token has been at, including the starting one, sorted by rows.
+    pub async fn trail(&self) -> Vec<(usize, usize)> {
+        let mut trail: Vec<(usize, usize)> = self.visited.iter().copied().collect();
+        trail.sort_by_key(|&(x, y)| (y, x));
+        trail
+    }
 
     async fn start(&mut self) {
         if !self.is_started {
@@ -85,45 +412,91 @@ impl Game {
     }
 
     async fn process_key(&mut self, key: Key) {
+        // Keys pushed after quitting are ignored
+        if self.is_finished {
+            return;
+        }
         self.start().await;
-        match key {
-            Key::Left => {
-                if self.coordinate.x > 0 {
-                    self.coordinate.x -= 1;
-                    self.logger.log(LogRecord::Moved(self.coordinate.x as usize, self.coordinate.y as usize)).await;
-                } else {
-                    self.logger.log(LogRecord::Stayed).await;
-                }
+        match key.delta() {
+            Some((dx, dy)) => self.shift(dx * self.step as i64, dy * self.step as i64, LogRecord::Moved).await,
+            None => {
+                self.is_finished = true;
+                self.logger.log(LogRecord::Finished).await;
             },
-            Key::Right => {
-                if self.coordinate.x < self.board_size.0 as i64 - 1 {
-                    self.coordinate.x += 1;
-                    self.logger.log(LogRecord::Moved(self.coordinate.x as usize, self.coordinate.y as usize)).await;
-                } else {
-                    self.logger.log(LogRecord::Stayed).await;
+        }
+    }
+
+    /// Takes back the last move, repeated calls walk further back until the start.
+    pub async fn undo(&mut self) {
+        if self.is_finished {
+            return;
+        }
+        if let Some((x, y)) = self.history.pop() {
+            self.coordinate = Coordinate { x: x as i64, y: y as i64 };
+            self.logger.log(LogRecord::Undone(x, y)).await;
+        }
+    }
+
+    async fn move_by(&mut self, dx: i64, dy: i64) {
+        if self.is_finished {
+            return;
+        }
+        self.start().await;
+        self.shift(dx, dy, LogRecord::Jumped).await;
+    }
+
+    // Walks one cell at a time and stops in front of the first obstacle. Each axis moves on its own
+    // as far as the board allows, so a diagonal move blocked on one axis still moves along the other
+    fn walk(&self, from: (i64, i64), dx: i64, dy: i64) -> Walk {
+        let (width, height) = (self.board_size.0 as i64, self.board_size.1 as i64);
+        let (mut x, mut y) = from;
+        let (mut remaining_x, mut remaining_y) = (dx.abs(), dy.abs());
+        let mut travelled = 0;
+        let mut blocked = false;
+        while remaining_x > 0 || remaining_y > 0 {
+            let mut next_x = if remaining_x > 0 { x + dx.signum() } else { x };
+            let mut next_y = if remaining_y > 0 { y + dy.signum() } else { y };
+            if self.wrap {
+                (next_x, next_y) = (next_x.rem_euclid(width), next_y.rem_euclid(height));
+            } else {
+                if !(0..width).contains(&next_x) {
+                    (next_x, remaining_x) = (x, 0);
                 }
-            },
-            Key::Up => {
-                if self.coordinate.y > 0 {
-                    self.coordinate.y -= 1;
-                    self.logger.log(LogRecord::Moved(self.coordinate.x as usize, self.coordinate.y as usize)).await;
-                } else {
-                    self.logger.log(LogRecord::Stayed).await;
+                if !(0..height).contains(&next_y) {
+                    (next_y, remaining_y) = (y, 0);
                 }
-            },
-            Key::Down => {
-                if self.coordinate.y < self.board_size.1 as i64 - 1 {
-                    self.coordinate.y += 1;
-                    self.logger.log(LogRecord::Moved(self.coordinate.x as usize, self.coordinate.y as usize)).await;
-                } else {
-                    self.logger.log(LogRecord::Stayed).await;
+                if (next_x, next_y) == (x, y) {
+                    break;
                 }
-            },
-            Key::Quit => {
-                self.logger.log(LogRecord::Finished).await;
-            },
-            
+            }
+            if self.obstacles.contains(&(next_x as usize, next_y as usize)) {
+                blocked = true;
+                break;
+            }
+            (x, y) = (next_x, next_y);
+            (remaining_x, remaining_y) = ((remaining_x - 1).max(0), (remaining_y - 1).max(0));
+            travelled += 1;
+        }
+        Walk { end: (x, y), travelled, blocked }
+    }
+
+    // Logs the cell the token ends at with the given record
+    async fn shift(&mut self, dx: i64, dy: i64, moved: fn(usize, usize) -> LogRecord) {
+        let Walk { end: (x, y), travelled, blocked } = self.walk((self.coordinate.x, self.coordinate.y), dx, dy);
+        if travelled > 0 {
+            self.history.push(self.position().await);
+            self.coordinate = Coordinate { x, y };
+            self.moves_made += 1;
+            self.distance += travelled;
+            self.visited.insert((x as usize, y as usize));
+            self.logger.log(moved(x as usize, y as usize)).await;
+        } else {
+            let record = match Key::towards(dx, dy) {
+                Some(key) if !blocked => LogRecord::HitWall(key),
+                _ => LogRecord::Stayed,
+            };
+            self.logger.log(record).await;
         }
     }
-    
+
 }
\ No newline at end of file
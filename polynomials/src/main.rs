@@ -1,9 +1,37 @@
-use std::collections::HashMap;
-use std::ops::Add;
-use std::cmp::PartialEq;
+// The demo in main uses only a part of the library, the rest is covered by the tests
+#![cfg_attr(not(test), allow(dead_code))]
 
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+use std::ops::{Add, AddAssign, Mul};
+use std::cmp::{PartialEq, Reverse};
+use std::fmt;
+
+// Maps each variable of a monomial to its exponent, a constant has no variables at all
+type Monomial = BTreeMap<String, i32>;
+
+// Coefficients never wrap around silently, an overflow stops the computation with a clear message
+fn add_coefficients(a: i64, b: i64) -> i64 {
+    a.checked_add(b).unwrap_or_else(|| panic!("Coefficient overflow while adding {} and {}", a, b))
+}
+
+fn multiply_coefficients(a: i64, b: i64) -> i64 {
+    a.checked_mul(b).unwrap_or_else(|| panic!("Coefficient overflow while multiplying {} by {}", a, b))
+}
+
+// Exponents of the same variable add up, the ones that cancel out leave the variable out
+fn multiply_monomials(a: &Monomial, b: &Monomial) -> Monomial {
+    let mut product = a.clone();
+    for (variable, exponent) in b {
+        *product.entry(variable.clone()).or_insert(0) += exponent;
+    }
+    product.retain(|_, exponent| *exponent != 0);
+    product
+}
+
+#[derive(Clone)]
 pub struct Polynomial {
-    polinomial: HashMap<String, HashMap<i32, i64>>,
+    polinomial: HashMap<Monomial, i64>,
 }
 
 impl Polynomial {
@@ -11,100 +39,238 @@ impl Polynomial {
         PolynomialBuilder::default()
     }
 
-    fn add_monomial(&mut self, coefficient: i64, term: &str, exponent: i32)  {
-        let terms = self.polinomial.get_mut(term).unwrap();
-        let target_coefficient = terms.get_mut(&exponent);
-        match target_coefficient {
-            Some(target) => {
-                *target += coefficient;
-            },
-            None => {
-                terms.insert(exponent, coefficient);
+    /// A polynomial without variables, its only term is keyed by the monomial with no variables.
+    pub fn constant(c: i64) -> Polynomial {
+        Polynomial::builder().add_term(c, &[]).build()
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.polinomial.is_empty()
+    }
+
+    /// Iterates over the monomials with their coefficients in the order they are displayed in.
+    /// A monomial maps each of its variables to the exponent, a constant has no variables.
+    pub fn monomials(&self) -> impl Iterator<Item = (&BTreeMap<String, i32>, i64)> {
+        let mut terms: Vec<(&Monomial, i64)> = self.polinomial.iter()
+            .map(|(monomial, coefficient)| (monomial, *coefficient))
+            .collect();
+        terms.sort_by_key(|(monomial, _)| Self::display_order(monomial));
+        terms.into_iter()
+    }
+
+    /// The highest sum of exponents in any monomial, the zero polynomial has degree 0.
+    pub fn degree(&self) -> i32 {
+        self.polinomial.keys().map(Self::monomial_degree).max().unwrap_or(0)
+    }
+
+    /// The coefficient of the monomial of the highest degree, ties go to the one written first.
+    pub fn leading_coefficient(&self) -> Option<i64> {
+        let degree = self.degree();
+        self.polinomial.iter()
+            .filter(|(monomial, _)| Self::monomial_degree(monomial) == degree)
+            .min_by_key(|(monomial, _)| Self::display_order(monomial))
+            .map(|(_, coefficient)| *coefficient)
+    }
+
+    fn monomial_degree(monomial: &Monomial) -> i32 {
+        monomial.values().sum()
+    }
+
+    // Orders monomials by their variables and then from the highest exponent, the constant goes last
+    fn display_order(monomial: &Monomial) -> (bool, Vec<(String, Reverse<i32>)>) {
+        let factors = monomial.iter()
+            .map(|(variable, exponent)| (variable.clone(), Reverse(*exponent)))
+            .collect();
+        (monomial.is_empty(), factors)
+    }
+
+    pub fn scale(&self, factor: i64) -> Polynomial {
+        self.map_coefficients(|coefficient| multiply_coefficients(coefficient, factor))
+    }
+
+    /// Applies the function to every coefficient, the terms whose coefficient becomes zero are dropped.
+    pub fn map_coefficients<F: Fn(i64) -> i64>(&self, f: F) -> Polynomial {
+        let mut result = Polynomial {
+            polinomial: self.polinomial.iter()
+                .map(|(monomial, coefficient)| (monomial.clone(), f(*coefficient)))
+                .collect(),
+        };
+        result.clear_zero_terms();
+        result
+    }
+
+    /// Reduces every coefficient into `[0, p)`, e.g. to compute over the finite field of a prime `p`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `p` is not positive.
+    pub fn reduce_mod(&self, p: i64) -> Polynomial {
+        assert!(p > 0, "The modulus has to be positive, got {}", p);
+        self.map_coefficients(|coefficient| coefficient.rem_euclid(p))
+    }
+
+    /// Long division of polynomials in a single variable, returns the quotient and the remainder.
+    pub fn div_rem(&self, divisor: &Polynomial) -> Result<(Polynomial, Polynomial), String> {
+        let variables: BTreeSet<&String> = self.polinomial.keys().chain(divisor.polinomial.keys())
+            .flat_map(|monomial| monomial.keys())
+            .collect();
+        if variables.len() > 1 {
+            return Err("Division is supported only for polynomials in a single variable".to_string());
+        }
+        let divisor_coefficient = divisor.leading_coefficient().ok_or_else(|| "Division by zero polynomial".to_string())?;
+        let variable = variables.into_iter().next().cloned().unwrap_or_default();
+
+        let mut quotient = Polynomial::builder().build();
+        let mut remainder = self.clone();
+        while let Some(coefficient) = remainder.leading_coefficient() {
+            if remainder.degree() < divisor.degree() {
+                break;
+            }
+            if coefficient % divisor_coefficient != 0 {
+                return Err(format!("Cannot divide the coefficient {} by {} over integers", coefficient, divisor_coefficient));
             }
+            let term = Polynomial::builder()
+                .add(coefficient / divisor_coefficient, &variable, remainder.degree() - divisor.degree())
+                .build();
+            remainder += (divisor.clone() * term.clone()).scale(-1);
+            quotient += term;
         }
+        Ok((quotient, remainder))
     }
 
-    fn clear_zero_terms(&mut self) {
-        let mut terms_to_remove = Vec::new();
-        for (term, value) in &mut self.polinomial {
-            let mut exponents_to_remove = Vec::new();
-            for (exponent, coefficient) in value.iter() {
-                if *coefficient == 0 {
-                    exponents_to_remove.push(*exponent);
-                }
-            }
-            
-            for exponent in exponents_to_remove {
-                value.remove(&exponent);
+    pub fn evaluate(&self, values: &HashMap<String, i64>) -> Result<i64, String> {
+        let mut result = 0;
+        for (monomial, coefficient) in &self.polinomial {
+            let mut term_value = *coefficient;
+            for (variable, exponent) in monomial {
+                let value = values.get(variable).ok_or_else(|| format!("Missing value of variable {}", variable))?;
+                let exponent = u32::try_from(*exponent).map_err(|_| format!("Cannot evaluate {}^{} over integers", variable, exponent))?;
+                term_value = value.checked_pow(exponent)
+                    .and_then(|power| term_value.checked_mul(power))
+                    .ok_or_else(|| "Overflow while evaluating the polynomial".to_string())?;
             }
+            result = i64::checked_add(result, term_value).ok_or_else(|| "Overflow while evaluating the polynomial".to_string())?;
+        }
+        Ok(result)
+    }
 
-            if value.is_empty() {
-                terms_to_remove.push(term.clone());
+    pub fn derivative(&self, var: &str) -> Polynomial {
+        let mut result = Polynomial::builder().build();
+        for (monomial, coefficient) in &self.polinomial {
+            if let Some(&exponent) = monomial.get(var) {
+                let mut derived = monomial.clone();
+                if exponent == 1 {
+                    derived.remove(var);
+                } else {
+                    derived.insert(var.to_string(), exponent - 1);
+                }
+                result.add_monomial(multiply_coefficients(*coefficient, i64::from(exponent)), derived);
             }
         }
-        for term in terms_to_remove {
-            self.polinomial.remove(&term);
+        result.clear_zero_terms();
+        result
+    }
+
+    fn add_monomial(&mut self, coefficient: i64, monomial: Monomial)  {
+        let target_coefficient = self.polinomial.get_mut(&monomial);
+        match target_coefficient {
+            Some(target) => {
+                *target = add_coefficients(*target, coefficient);
+            },
+            None => {
+                self.polinomial.insert(monomial, coefficient);
+            }
         }
     }
+
+    fn clear_zero_terms(&mut self) {
+        self.polinomial.retain(|_, coefficient| *coefficient != 0);
+    }
 }
 
 impl Add for Polynomial {
     type Output = Polynomial;
 
     fn add(self, other: Polynomial) -> Polynomial {
+        let mut result = self;
+        result += other;
+        result
+    }
+}
+
+impl AddAssign for Polynomial {
+    fn add_assign(&mut self, other: Polynomial) {
+        for (monomial, coefficient) in other.polinomial {
+            self.add_monomial(coefficient, monomial);
+        }
+        self.clear_zero_terms();
+    }
+}
+
+impl Mul for Polynomial {
+    type Output = Polynomial;
+
+    fn mul(self, other: Polynomial) -> Polynomial {
         let mut result = Polynomial::builder().build();
-        result.polinomial = self.polinomial.clone();
-        for (key, value) in other.polinomial {
-            let similar_terms = self.polinomial.get(&key);
-            match similar_terms {
-                Some(target) => {
-                    for (exponent, coefficient) in value {
-                        result.add_monomial(coefficient, &key, exponent);
-                    }
-                },
-                None => {
-                    result.polinomial.insert(key, value);
-                }
-            };
-            
+        for (monomial, coefficient) in &self.polinomial {
+            for (other_monomial, other_coefficient) in &other.polinomial {
+                let product = multiply_monomials(monomial, other_monomial);
+                result.add_monomial(multiply_coefficients(*coefficient, *other_coefficient), product);
+            }
         }
         result.clear_zero_terms();
         result
     }
 }
 
+impl fmt::Display for Polynomial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0");
+        }
+        for (index, (monomial, coefficient)) in self.monomials().enumerate() {
+            let sign = match (index, coefficient < 0) {
+                (0, false) => "",
+                (0, true) => "-",
+                (_, false) => " + ",
+                (_, true) => " - ",
+            };
+            write!(f, "{}", sign)?;
+            if coefficient.unsigned_abs() != 1 || monomial.is_empty() {
+                write!(f, "{}", coefficient.unsigned_abs())?;
+            }
+            for (variable, exponent) in monomial {
+                match exponent {
+                    1 => write!(f, "{}", variable)?,
+                    _ => write!(f, "{}^{}", variable, exponent)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 impl PartialEq for Polynomial {
+    // Zero terms are cleared after every operation, so equal polynomials have the very same terms
     fn eq(&self, other: &Polynomial) -> bool {
-        for (key, value) in &self.polinomial {
-            let similar_terms = other.polinomial.get(key);
-            match similar_terms {
-                Some(target) => {
-                    for (exponent, coefficient) in value {
-                        let target_coefficient = target.get(exponent);
-                        match target_coefficient {
-                            Some(target) => {
-                                if target != coefficient {
-                                    return false;
-                                }
-                            },
-                            None => {
-                                return false;
-                            }
-                        }
-                    }
-                },
-                None => {
-                    return false;
-                }
-            };
-            
+        self.polinomial == other.polinomial
+    }
+}
+
+impl Eq for Polynomial {}
+
+impl Hash for Polynomial {
+    // The terms are hashed in the display order, so the order they were added in does not matter
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for term in self.monomials() {
+            term.hash(state);
         }
-        true
     }
 }
+
 #[derive(Default)]
 pub struct PolynomialBuilder {
-    polinomial: HashMap<String, HashMap<i32, i64>>,
+    polinomial: HashMap<Monomial, i64>,
 }
 
 impl PolynomialBuilder {
@@ -114,24 +280,26 @@ impl PolynomialBuilder {
         }
     }
 
-    pub fn add(mut self, coefficient: i64, term: &str, exponent: i32) -> Self {
-        let similar_terms = self.polinomial.get_mut(term);
-        match similar_terms {
-            Some(terms) => {
-                let target_coefficient = terms.get_mut(&exponent);
-                match target_coefficient {
-                    Some(target) => {
-                        *target += coefficient;
-                    },
-                    None => {
-                        terms.insert(exponent, coefficient);
-                    }
-                }
+    pub fn add(self, coefficient: i64, term: &str, exponent: i32) -> Self {
+        self.add_term(coefficient, &[(term, exponent)])
+    }
+
+    /// Adds a monomial made of several variables, e.g. `2x^2y` is `add_term(2, &[("x", 2), ("y", 1)])`.
+    pub fn add_term(mut self, coefficient: i64, factors: &[(&str, i32)]) -> Self {
+        let mut monomial = Monomial::new();
+        for (variable, exponent) in factors {
+            *monomial.entry(variable.to_string()).or_insert(0) += exponent;
+        }
+        // Any variable to the power of zero is just one
+        monomial.retain(|_, exponent| *exponent != 0);
+
+        let target_coefficient = self.polinomial.get_mut(&monomial);
+        match target_coefficient {
+            Some(target) => {
+                *target = add_coefficients(*target, coefficient);
             },
             None => {
-                let mut exponent_to_coefficient = HashMap::new();
-                exponent_to_coefficient.insert(exponent, coefficient);
-                self.polinomial.insert(term.to_string(), exponent_to_coefficient);
+                self.polinomial.insert(monomial, coefficient);
             }
         }
         self
@@ -146,7 +314,6 @@ impl PolynomialBuilder {
     }
 }
 
-
 fn main() {
     let a = Polynomial::builder()
         .add(1, "x", 2)
@@ -154,19 +321,265 @@ fn main() {
         .add(2, "x", 2)
         .add(3, "y", 3)
         .build();
-    //let builder = builder.add(1, "x", 2).add(4, "x", 5);
-    //let builder = builder.add(2, "x", 2).add(3, "y", 3);
     let b = Polynomial::builder()
         .add(10, "x", 3)
         .add(-2, "x", 5)
         .add(2, "z", 2)
         .add(3, "z", 3)
         .build();
-    assert!(a != b);
-    let c = a + b;
-    let a = Polynomial::builder()
-        .add(3, "x", 3)
-        .add(3, "y", 5)
-        .add(4, "y", 4)
-        .build();
+    println!("a = {}", a);
+    println!("b = {}", b);
+    let sum = a.clone() + b.clone();
+    println!("a + b = {}", sum);
+    println!("a * b = {}", a * b);
+    println!("d/dx (a + b) = {}", sum.derivative("x"));
+    let values = HashMap::from([("x".to_string(), 2), ("y".to_string(), 1), ("z".to_string(), -1)]);
+    match sum.evaluate(&values) {
+        Ok(value) => println!("a + b at x = 2, y = 1, z = -1 is {}", value),
+        Err(message) => println!("{}", message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::collections::hash_map::DefaultHasher;
+
+    fn near_max() -> Polynomial {
+        Polynomial::builder().add(i64::MAX - 1, "x", 1).build()
+    }
+
+    #[test]
+    fn adds_and_compares() {
+        let a = Polynomial::builder()
+            .add(1, "x", 2)
+            .add(4, "x", 5)
+            .add(2, "x", 2)
+            .add(3, "y", 3)
+            .build();
+        let b = Polynomial::builder()
+            .add(10, "x", 3)
+            .add(-2, "x", 5)
+            .add(2, "z", 2)
+            .add(3, "z", 3)
+            .build();
+        assert!(a != b);
+        let expected = Polynomial::builder()
+            .add(3, "x", 2)
+            .add(10, "x", 3)
+            .add(2, "x", 5)
+            .add(3, "y", 3)
+            .add(2, "z", 2)
+            .add(3, "z", 3)
+            .build();
+        assert!(a + b == expected);
+
+        let a = Polynomial::builder().add(1, "x", 2).build();
+        let b = Polynomial::builder().add(1, "x", 2).add(1, "x", 3).build();
+        assert!(a != b);
+        assert!(b != a);
+        assert!(Polynomial::builder().build() != a);
+    }
+
+    #[test]
+    fn multiplies() {
+        let a = Polynomial::builder()
+            .add(3, "x", 3)
+            .add(3, "y", 5)
+            .add(4, "y", 4)
+            .build();
+        let doubled = a * Polynomial::builder().add(2, "y", 0).build();
+        assert!(doubled == Polynomial::builder().add(6, "x", 3).add(6, "y", 5).add(8, "y", 4).build());
+
+        let product = Polynomial::builder().add(1, "x", 2).add(2, "x", 1).build()
+            * Polynomial::builder().add(1, "x", 1).add(1, "x", 0).build();
+        assert_eq!(product.evaluate(&HashMap::from([("x".to_string(), 2)])), Ok(8 + 12 + 4));
+        let expected = Polynomial::builder()
+            .add(1, "x", 3)
+            .add(3, "x", 2)
+            .add(2, "x", 1)
+            .build();
+        assert!(product == expected);
+    }
+
+    #[test]
+    fn evaluates() {
+        let p = Polynomial::builder().add(3, "x", 2).add(1, "y", 1).build();
+        let values = HashMap::from([("x".to_string(), 2), ("y".to_string(), 5)]);
+        assert_eq!(p.evaluate(&values), Ok(17));
+        let values = HashMap::from([("x".to_string(), 2)]);
+        assert_eq!(p.evaluate(&values), Err("Missing value of variable y".to_string()));
+    }
+
+    #[test]
+    fn derives() {
+        let p = Polynomial::builder().add(4, "x", 5).add(1, "x", 2).add(7, "x", 0).add(2, "y", 3).build();
+        let expected = Polynomial::builder().add(20, "x", 4).add(2, "x", 1).build();
+        assert!(p.derivative("x") == expected);
+        assert!(p.derivative("z") == Polynomial::builder().build());
+    }
+
+    #[test]
+    fn displays() {
+        let p = Polynomial::builder()
+            .add(3, "y", 3)
+            .add(2, "x", 2)
+            .add(4, "x", 5)
+            .add(-5, "x", 0)
+            .add(-1, "y", 1)
+            .add(1, "x", 1)
+            .build();
+        assert_eq!(p.to_string(), "4x^5 + 2x^2 + x + 3y^3 - y - 5");
+        assert_eq!(Polynomial::builder().add(-1, "x", 2).build().to_string(), "-x^2");
+        assert_eq!(Polynomial::builder().build().to_string(), "0");
+    }
+
+    #[test]
+    fn multivariate_monomials() {
+        let product = Polynomial::builder().add(1, "x", 1).build() * Polynomial::builder().add(1, "y", 1).build();
+        let sum = Polynomial::builder().add(1, "x", 1).add(1, "y", 1).build();
+        assert!(product != sum);
+        assert!(product == Polynomial::builder().add_term(1, &[("x", 1), ("y", 1)]).build());
+        assert_eq!(product.to_string(), "xy");
+        let sum = Polynomial::builder().add_term(2, &[("x", 2), ("y", 1)]).build()
+            + Polynomial::builder().add_term(3, &[("y", 1), ("x", 2)]).build();
+        assert!(sum == Polynomial::builder().add_term(5, &[("x", 2), ("y", 1)]).build());
+        assert_eq!(sum.to_string(), "5x^2y");
+        assert!(Polynomial::builder().add(7, "x", 0).build() == Polynomial::builder().add(7, "y", 0).build());
+        assert_eq!(sum.derivative("x").to_string(), "10xy");
+    }
+
+    #[test]
+    fn degree_and_leading_coefficient() {
+        let p = Polynomial::builder().add(3, "x", 2).add(1, "x", 5).build();
+        assert_eq!((p.degree(), p.leading_coefficient()), (5, Some(1)));
+        let p = Polynomial::builder().add_term(-4, &[("x", 2), ("y", 3)]).add(9, "z", 4).add(2, "x", 0).build();
+        assert_eq!((p.degree(), p.leading_coefficient()), (5, Some(-4)));
+        let zero = Polynomial::builder().build();
+        assert_eq!((zero.degree(), zero.leading_coefficient()), (0, None));
+    }
+
+    #[test]
+    #[should_panic(expected = "Coefficient overflow while adding")]
+    fn addition_overflow_panics() {
+        let _ = near_max() + near_max();
+    }
+
+    #[test]
+    #[should_panic(expected = "Coefficient overflow while adding")]
+    fn builder_overflow_panics() {
+        Polynomial::builder().add(i64::MAX, "x", 1).add(1, "x", 1).build();
+    }
+
+    #[test]
+    #[should_panic(expected = "Coefficient overflow while multiplying")]
+    fn multiplication_overflow_panics() {
+        let _ = near_max() * Polynomial::builder().add(2, "y", 1).build();
+    }
+
+    #[test]
+    fn evaluation_overflow_is_an_error() {
+        let values = HashMap::from([("x".to_string(), 2)]);
+        assert_eq!(near_max().evaluate(&values), Err("Overflow while evaluating the polynomial".to_string()));
+    }
+
+    #[test]
+    fn scales_and_accumulates() {
+        let p = Polynomial::builder().add(3, "x", 2).add_term(-2, &[("x", 1), ("y", 1)]).build();
+        assert!(p.scale(0) == Polynomial::builder().build());
+        assert_eq!(p.scale(-3).to_string(), "-9x^2 + 6xy");
+        let q = || Polynomial::builder().add(-3, "x", 2).add(1, "y", 1).build();
+        let mut accumulated = Polynomial::builder().add(3, "x", 2).add_term(-2, &[("x", 1), ("y", 1)]).build();
+        accumulated += q();
+        assert!(accumulated == p + q());
+        assert_eq!(accumulated.to_string(), "-2xy + y");
+    }
+
+    #[test]
+    fn iterates_monomials_in_display_order() {
+        let p = Polynomial::builder().add(5, "x", 0).add(2, "y", 1).add_term(-1, &[("x", 2), ("y", 3)]).build();
+        let terms: Vec<(Vec<(&str, i32)>, i64)> = p.monomials()
+            .map(|(monomial, coefficient)| (monomial.iter().map(|(variable, exponent)| (variable.as_str(), *exponent)).collect(), coefficient))
+            .collect();
+        assert_eq!(terms, [(vec![("x", 2), ("y", 3)], -1), (vec![("y", 1)], 2), (vec![], 5)]);
+    }
+
+    #[test]
+    fn drops_canceled_terms() {
+        let canceled = Polynomial::builder().add(5, "x", 2).add(-5, "x", 2).build();
+        assert!(canceled == Polynomial::builder().build());
+        let canceled = Polynomial::builder().add(1, "x", 2).add(3, "x", 3).build() + Polynomial::builder().add(-3, "x", 3).build();
+        assert!(canceled == Polynomial::builder().add(1, "x", 2).build());
+    }
+
+    #[test]
+    fn divides_with_remainder() {
+        let dividend = Polynomial::builder().add(1, "x", 2).add(-1, "x", 0).build();
+        let divisor = Polynomial::builder().add(1, "x", 1).add(-1, "x", 0).build();
+        let (quotient, remainder) = dividend.div_rem(&divisor).expect("both are polynomials in x");
+        assert_eq!((quotient.to_string(), remainder.to_string()), ("x + 1".to_string(), "0".to_string()));
+        let dividend = Polynomial::builder().add(2, "x", 3).add(3, "x", 1).add(1, "x", 0).build();
+        let (quotient, remainder) = dividend.div_rem(&divisor).expect("both are polynomials in x");
+        assert_eq!((quotient.to_string(), remainder.to_string()), ("2x^2 + 2x + 5".to_string(), "6".to_string()));
+        assert!(dividend.div_rem(&Polynomial::builder().build()).is_err());
+        assert!(dividend.div_rem(&Polynomial::builder().add(1, "y", 1).build()).is_err());
+        assert!(dividend.div_rem(&Polynomial::builder().add(3, "x", 2).build()).is_err());
+    }
+
+    #[test]
+    fn constants_and_zero() {
+        assert!(Polynomial::constant(0).is_zero());
+        assert!(!Polynomial::constant(5).is_zero());
+        assert!(Polynomial::builder().add(2, "x", 1).add(-2, "x", 1).build().is_zero());
+        assert_eq!(Polynomial::constant(5).evaluate(&HashMap::new()), Ok(5));
+        assert_eq!(Polynomial::constant(5).evaluate(&HashMap::from([("x".to_string(), 7)])), Ok(5));
+        assert!(Polynomial::constant(-7) == Polynomial::builder().add(-7, "x", 0).build());
+        assert_eq!((Polynomial::constant(3) * Polynomial::builder().add(1, "x", 2).build()).to_string(), "3x^2");
+    }
+
+    #[test]
+    fn maps_coefficients() {
+        let p = Polynomial::builder().add(4, "x", 3).add(6, "x", 2).add(-2, "x", 1).add(9, "x", 0).build();
+        assert_eq!(p.map_coefficients(|c| c % 3).to_string(), "x^3 - 2x");
+        assert_eq!(p.map_coefficients(i64::abs).to_string(), "4x^3 + 6x^2 + 2x + 9");
+        assert!(p.map_coefficients(|_| 0).is_zero());
+    }
+
+    #[test]
+    fn hashes_consistently_with_equality() {
+        let hash = |p: &Polynomial| {
+            let mut hasher = DefaultHasher::new();
+            p.hash(&mut hasher);
+            hasher.finish()
+        };
+        let first = Polynomial::builder().add(1, "x", 2).add_term(3, &[("x", 1), ("y", 1)]).add(-4, "z", 0).build();
+        let second = Polynomial::builder().add(-4, "z", 0).add_term(3, &[("y", 1), ("x", 1)]).add(1, "x", 2).build();
+        assert!(first == second);
+        assert_eq!(hash(&first), hash(&second));
+        let distinct: HashSet<Polynomial> = [first, second, Polynomial::constant(-4)].into_iter().collect();
+        assert_eq!(distinct.len(), 2);
+    }
+
+    #[test]
+    fn reduces_modulo_a_prime() {
+        let p = Polynomial::builder().add(7, "x", 2).add(3, "x", 1).build();
+        assert!(p.reduce_mod(5) == Polynomial::builder().add(2, "x", 2).add(3, "x", 1).build());
+        assert_eq!(p.reduce_mod(5).to_string(), "2x^2 + 3x");
+        let p = Polynomial::builder().add(10, "x", 3).add(-1, "x", 1).add(-6, "x", 0).build();
+        assert_eq!(p.reduce_mod(5).to_string(), "4x + 4");
+        assert!(p.reduce_mod(1).is_zero());
+    }
+
+    #[test]
+    #[should_panic(expected = "The modulus has to be positive, got 0")]
+    fn zero_modulus_panics() {
+        Polynomial::constant(3).reduce_mod(0);
+    }
+
+    #[test]
+    #[should_panic(expected = "The modulus has to be positive, got -5")]
+    fn negative_modulus_panics() {
+        Polynomial::constant(3).reduce_mod(-5);
+    }
 }
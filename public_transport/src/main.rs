@@ -1,5 +1,9 @@
+// The demo in main uses only a part of the library, the rest is covered by the tests
+#![cfg_attr(not(test), allow(dead_code))]
+
 use std::sync::{Arc, Mutex};
-use std::collections::{HashSet, HashMap, VecDeque, BTreeMap};
+use std::collections::{HashSet, HashMap, VecDeque, BTreeMap, BinaryHeap};
+use std::cmp::Reverse;
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct City {
@@ -19,26 +23,52 @@ pub struct Road {
     point_b: Arc<City>,
 }
 
+impl Road {
+    fn connects(&self, a: &Arc<City>, b: &Arc<City>) -> bool {
+        (Arc::ptr_eq(&self.point_a, a) && Arc::ptr_eq(&self.point_b, b)) ||
+        (Arc::ptr_eq(&self.point_a, b) && Arc::ptr_eq(&self.point_b, a))
+    }
+}
+
+// Finds the travel time of the fastest road between two cities, if they are connected
+fn road_travel_time(roads: &HashSet<Arc<Road>>, a: &Arc<City>, b: &Arc<City>) -> Option<u32> {
+    roads.iter().filter(|road| road.connects(a, b)).map(|road| road.travel_time).min()
+}
+
 pub struct Bus {
     id: u32,
+    name: Option<String>, // how operators call the line, the id stays the key for scheduling
     route: Mutex<VecDeque<Arc<City>>>,
     upcoming_stops: Mutex<HashSet<Arc<City>>>,
-    //total_route: VecDeque<Arc<City>>,
+    total_route: VecDeque<Arc<City>>,
+    departure_time: u32, // when the bus stands at the first stop of its route
+    stops_passed: Mutex<usize>,
     time_people_getting_off: Mutex<BTreeMap<Arc<City>, u32>>,
     finished: Mutex<bool>,
+    capacity: u32,
+    occupancy: Mutex<u32>,
+    looping: bool, // returns from the last stop to the first one and starts over instead of finishing
+    reversing: bool, // drives the route backward from the last stop, then forward again, instead of finishing
 }
 
 impl Bus {
-    pub fn new(route: Vec<Arc<City>>, id: u32) -> Self {
+    pub fn new(route: Vec<Arc<City>>, id: u32, capacity: u32, departure_time: u32, looping: bool, reversing: bool) -> Self {
         let route_deque = VecDeque::from(route.to_vec());
         let upcoming_stops = Mutex::new(route.iter().cloned().collect());
         Bus {
             id,
+            name: None,
             route: Mutex::new(route_deque.clone()),
             upcoming_stops,
-            //total_route: route_deque,
+            total_route: route_deque,
+            departure_time,
+            stops_passed: Mutex::new(0),
             time_people_getting_off: Mutex::new(BTreeMap::new()),
             finished: Mutex::new(false),
+            capacity,
+            occupancy: Mutex::new(0),
+            looping,
+            reversing,
         }
     }
 
@@ -46,14 +76,53 @@ impl Bus {
         self.id
     }
 
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
     pub fn is_upcoming_stop(&self, city: Arc<City>) -> bool {
         self.upcoming_stops.lock().unwrap().contains(&city) && city != self.current_stop()
     }
 
+    pub fn free_seats(&self) -> u32 {
+        self.capacity - *self.occupancy.lock().unwrap()
+    }
+
+    pub fn occupancy(&self) -> u32 {
+        *self.occupancy.lock().unwrap()
+    }
+
+    fn board(&self, count: u32) {
+        *self.occupancy.lock().unwrap() += count;
+    }
+
+    fn alight(&self, count: u32) {
+        let mut occupancy = self.occupancy.lock().unwrap();
+        *occupancy = occupancy.saturating_sub(count);
+    }
+
     fn current_stop(&self) -> Arc<City> {
         self.route.lock().unwrap().front().unwrap().clone()
     }
 
+    /// Puts the bus back to its first stop, empty and ready to depart again.
+    fn reset(&self) {
+        *self.route.lock().unwrap() = self.total_route.clone();
+        *self.upcoming_stops.lock().unwrap() = self.total_route.iter().cloned().collect();
+        *self.stops_passed.lock().unwrap() = 0;
+        self.time_people_getting_off.lock().unwrap().clear();
+        *self.finished.lock().unwrap() = false;
+        *self.occupancy.lock().unwrap() = 0;
+    }
+
+    /// Catches the route up with the city the bus has just reached.
+    fn arrive_at(&self, city: &Arc<City>) {
+        // Stops where nobody got on or off have no event, the bus passed them on the way here
+        while self.route.lock().unwrap().front().is_some_and(|stop| !Arc::ptr_eq(stop, city)) {
+            self.move_to_next();
+        }
+    }
+
     pub fn move_to_next(&self) {
         let mut finished = self.finished.lock().unwrap();
         if *finished {
@@ -63,7 +132,25 @@ impl Bus {
         let mut upcoming_stops = self.upcoming_stops.lock().unwrap();
 
         if let Some(next_city) = route.pop_front() {
-            upcoming_stops.remove(&next_city);
+            // The next visit of this city, if any, happens at a different time
+            self.time_people_getting_off.lock().unwrap().remove(&next_city);
+            *self.stops_passed.lock().unwrap() += 1;
+            if self.looping {
+                route.push_back(next_city);
+            } else {
+                upcoming_stops.remove(&next_city);
+            }
+            // At the terminus the return leg is appended, so people there can already board for it
+            if self.reversing && route.len() == 1 {
+                let terminus = route[0].clone();
+                let leg: Vec<Arc<City>> = if Arc::ptr_eq(&terminus, &self.total_route[0]) {
+                    self.total_route.iter().skip(1).cloned().collect()
+                } else {
+                    self.total_route.iter().rev().skip(1).cloned().collect()
+                };
+                route.extend(leg);
+                *upcoming_stops = route.iter().cloned().collect();
+            }
         } else {
             *finished = true;
         }
@@ -80,11 +167,8 @@ impl Bus {
         // Skipping the first city in the route as it's the current stop
         for city in self.route.lock().unwrap().iter().skip(1) {
             // Find the road between current_stop and the next city in the route
-            if let Some(road) = roads.iter().find(|road| {
-                (Arc::ptr_eq(&road.point_a, &current_stop) && Arc::ptr_eq(&road.point_b, city)) ||
-                (Arc::ptr_eq(&road.point_a, city) && Arc::ptr_eq(&road.point_b, &current_stop))
-            }) {
-                total_travel_time += road.travel_time;
+            if let Some(travel_time) = road_travel_time(roads, &current_stop, city) {
+                total_travel_time += travel_time;
 
                 // Check if we have reached the requested stop
                 if Arc::ptr_eq(city, &stop) {
@@ -97,6 +181,58 @@ impl Bus {
         total_travel_time
     }
 
+    /// Returns when the bus reaches the given city on the rest of its route, `None` if it does not go there anymore.
+    pub fn arrival_time(&self, roads: &HashSet<Arc<Road>>, city: &Arc<City>) -> Option<u32> {
+        if !self.route.lock().unwrap().iter().any(|stop| Arc::ptr_eq(stop, city)) {
+            return None;
+        }
+        let stops_passed = *self.stops_passed.lock().unwrap();
+
+        // Follow the route from its very beginning, a looping or reversing bus may already be several rounds in
+        let mut arrival_time = self.departure_time;
+        let mut index = 0;
+        loop {
+            let stop = self.nth_stop(index)?;
+            if index >= stops_passed && Arc::ptr_eq(stop, city) {
+                return Some(arrival_time);
+            }
+            arrival_time += road_travel_time(roads, stop, self.nth_stop(index + 1)?)?;
+            index += 1;
+        }
+    }
+
+    /// Passes the stops the bus drove through before the given time, there is no event for stops where nobody
+    /// got on or off, so the route would still list them as upcoming.
+    fn catch_up(&self, roads: &HashSet<Arc<Road>>, time: u32) {
+        while self.scheduled_arrival(roads, *self.stops_passed.lock().unwrap()).is_some_and(|arrival_time| arrival_time < time) {
+            self.move_to_next();
+        }
+    }
+
+    // When the bus reaches the stop it stands at after passing `index` stops since its departure
+    fn scheduled_arrival(&self, roads: &HashSet<Arc<Road>>, index: usize) -> Option<u32> {
+        let mut arrival_time = self.departure_time;
+        for passed in 0..index {
+            arrival_time += road_travel_time(roads, self.nth_stop(passed)?, self.nth_stop(passed + 1)?)?;
+        }
+        self.nth_stop(index)?;
+        Some(arrival_time)
+    }
+
+    /// The city the bus stands at after passing `index` stops since its departure.
+    fn nth_stop(&self, index: usize) -> Option<&Arc<City>> {
+        let stop_count = self.total_route.len();
+        if self.looping {
+            self.total_route.get(index % stop_count)
+        } else if self.reversing {
+            // One round trip goes there and back without repeating the terminus
+            let position = index % (2 * stop_count - 2);
+            self.total_route.get(position.min(2 * stop_count - 2 - position))
+        } else {
+            self.total_route.get(index)
+        }
+    }
+
 }
 
 #[derive(Clone)]
@@ -108,6 +244,10 @@ pub struct Event {
 }
 
 impl Event {
+    pub fn bus_id(&self) -> u32 {
+        self.bus.get_id()
+    }
+
     pub fn got_off(&self) -> u32 {
         self.got_off_count
     }
@@ -121,15 +261,47 @@ impl Event {
     }
 }
 
+/// Summary of how the network performed so far.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stats {
+    pub total_boarded: u32,
+    pub total_delivered: u32,
+    pub people_still_waiting: u32,
+    pub total_abandoned: u32,
+}
+
+type EventObserver = Box<dyn FnMut(&Event)>;
+
+// People who started waiting for the same connection at the same time
+struct PassengerGroup {
+    count: u32,
+    waiting_since: u32,
+    patience: u32, // how long they wait before giving up
+}
+
 pub struct Simulation {
     buses: Vec<Arc<Bus>>,
     roads: HashSet<Arc<Road>>,
+    cities: HashSet<Arc<City>>,
     // Maps each city to a record of destinations and the number of people waiting to travel there.
     // For each city (key), it holds a map of destination cities (inner key) and passenger counts (value).
     waiting_people: HashMap<Arc<City>, HashMap<Arc<City>, u32>>,
+    // The same people split into groups by when they came, the ones waiting the longest board first
+    passenger_groups: HashMap<(Arc<City>, Arc<City>), VecDeque<PassengerGroup>>,
     next_bus_id: u32,
     event_queue: BTreeMap<u32, BTreeMap<u32, Arc<Event>>>,
     current_time: u32,
+    total_boarded: u32,
+    total_delivered: u32,
+    total_wait_time: u64, // summed over everybody who has boarded
+    total_abandoned: u32,
+    event_observer: Option<EventObserver>,
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Simulation {
@@ -137,20 +309,42 @@ impl Simulation {
         Simulation {
             buses: Vec::new(),
             roads: HashSet::new(),
+            cities: HashSet::new(),
             waiting_people: HashMap::new(),
+            passenger_groups: HashMap::new(),
             next_bus_id: 0,
             event_queue: BTreeMap::new(),
             current_time: 0,
+            total_boarded: 0,
+            total_delivered: 0,
+            total_wait_time: 0,
+            total_abandoned: 0,
+            event_observer: None,
         }
     }
 
+    /// Adds a city, a name that is already taken returns the city added first, as cities are keyed by their names.
     pub fn new_city(&mut self, name: &str) -> Arc<City> {
-        Arc::new(City {
+        let city = Arc::new(City {
             name: name.to_string()
-        })
+        });
+        if let Some(existing) = self.cities.get(&city) {
+            return existing.clone();
+        }
+        self.cities.insert(city.clone());
+        city
     }
 
+    /// Connects two cities, a pair that is already connected keeps a single road with the faster travel time.
+    /// Every road takes at least one time unit, so a bus never stands at two stops at once.
     pub fn new_road(&mut self, a: &Arc<City>, b: &Arc<City>, travel_time: u32) -> Arc<Road> {
+        let travel_time = travel_time.max(1);
+        if let Some(existing) = self.roads.iter().filter(|road| road.connects(a, b)).min_by_key(|road| road.travel_time).cloned() {
+            if existing.travel_time <= travel_time {
+                return existing;
+            }
+            self.roads.remove(&existing);
+        }
         let road = Arc::new(Road {
             travel_time,
             point_a: a.clone(),
@@ -160,32 +354,143 @@ impl Simulation {
         road
     }
 
-    fn valid_route(&self, route: &Vec<Arc<City>>) {
+    // Lists the cities directly connected to the given one together with the travel times
+    fn neighbors(&self, city: &Arc<City>) -> Vec<(Arc<City>, u32)> {
+        self.roads.iter().filter_map(|road| {
+            if Arc::ptr_eq(&road.point_a, city) {
+                Some((road.point_b.clone(), road.travel_time))
+            } else if Arc::ptr_eq(&road.point_b, city) {
+                Some((road.point_a.clone(), road.travel_time))
+            } else {
+                None
+            }
+        }).collect()
+    }
+
+    /// Maps every city on a road to its neighbors and the travel times, each road is listed at both of its ends.
+    pub fn road_graph(&self) -> HashMap<Arc<City>, Vec<(Arc<City>, u32)>> {
+        let mut graph: HashMap<Arc<City>, Vec<(Arc<City>, u32)>> = HashMap::new();
+        for road in &self.roads {
+            graph.entry(road.point_a.clone()).or_default().push((road.point_b.clone(), road.travel_time));
+            graph.entry(road.point_b.clone()).or_default().push((road.point_a.clone(), road.travel_time));
+        }
+        graph
+    }
+
+    /// Finds the fastest way between two cities, returning the total travel time and the visited cities.
+    pub fn shortest_path(&self, from: &Arc<City>, to: &Arc<City>) -> Option<(u32, Vec<Arc<City>>)> {
+        let mut distances: HashMap<Arc<City>, u32> = HashMap::from([(from.clone(), 0)]);
+        let mut previous: HashMap<Arc<City>, Arc<City>> = HashMap::new();
+        let mut queue = BinaryHeap::from([Reverse((0, from.clone()))]);
+
+        while let Some(Reverse((distance, city))) = queue.pop() {
+            if Arc::ptr_eq(&city, to) {
+                let mut path = vec![city];
+                while let Some(city) = previous.get(path.last().unwrap()) {
+                    path.push(city.clone());
+                }
+                path.reverse();
+                return Some((distance, path));
+            }
+            if distance > distances[&city] {
+                continue; // A shorter way to this city was already processed
+            }
+            for (neighbor, travel_time) in self.neighbors(&city) {
+                let new_distance = distance + travel_time;
+                if distances.get(&neighbor).is_none_or(|&known| new_distance < known) {
+                    distances.insert(neighbor.clone(), new_distance);
+                    previous.insert(neighbor.clone(), city.clone());
+                    queue.push(Reverse((new_distance, neighbor)));
+                }
+            }
+        }
+        None
+    }
+
+    /// Checks that every city on a road can be reached from every other one.
+    pub fn is_connected(&self) -> bool {
+        let cities: HashSet<Arc<City>> = self.roads.iter()
+            .flat_map(|road| [road.point_a.clone(), road.point_b.clone()])
+            .collect();
+        let Some(start) = cities.iter().next() else {
+            return true;
+        };
+
+        let mut reached = HashSet::from([start.clone()]);
+        let mut queue = VecDeque::from([start.clone()]);
+        while let Some(city) = queue.pop_front() {
+            for (neighbor, _) in self.neighbors(&city) {
+                if reached.insert(neighbor.clone()) {
+                    queue.push_back(neighbor);
+                }
+            }
+        }
+        reached.len() == cities.len()
+    }
+
+    fn valid_route(&self, route: &[Arc<City>], looping: bool) -> Result<(), String> {
         if route.len() < 2 {
-            panic!("Invalid bus route: A bus must have at least two stops.");
+            return Err("Invalid bus route: A bus must have at least two stops.".to_string());
         }
 
-        let has_valid_roads = route.windows(2).all(|cities| {
-            self.roads.iter().any(|road| 
-                (Arc::ptr_eq(&road.point_a, &cities[0]) && Arc::ptr_eq(&road.point_b, &cities[1])) ||
-                (Arc::ptr_eq(&road.point_a, &cities[1]) && Arc::ptr_eq(&road.point_b, &cities[0]))
-            )
+        // A looping bus also drives from the last stop back to the first one
+        let closing_leg = [route[route.len() - 1].clone(), route[0].clone()];
+        let mut legs: Vec<&[Arc<City>]> = route.windows(2).collect();
+        if looping {
+            legs.push(&closing_leg);
+        }
+        let missing_road = legs.into_iter().find(|cities| {
+            !self.roads.iter().any(|road| road.connects(&cities[0], &cities[1]))
         });
 
-        if !has_valid_roads {
-            panic!("Invalid bus route: Not all consecutive stops in the route have existing roads between them.");
+        if let Some(cities) = missing_road {
+            return Err(format!("Invalid bus route: There is no road between {} and {}.", cities[0].name(), cities[1].name()));
         }
+        Ok(())
     }
 
     fn add_event(&mut self, event: Arc<Event>, time: u32) {
         let bus_id = event.bus.get_id();
-        self.event_queue.entry(time).or_insert_with(BTreeMap::new).insert(bus_id, event);
+        self.event_queue.entry(time).or_default().insert(bus_id, event);
+    }
+
+    /// Adds a bus with unlimited capacity and returns its id, or describes why the route is invalid.
+    pub fn new_bus(&mut self, route: &[&Arc<City>]) -> Result<u32, String> {
+        self.new_bus_with_capacity(route, u32::MAX)
+    }
+
+    pub fn new_bus_with_capacity(&mut self, route: &[&Arc<City>], capacity: u32) -> Result<u32, String> {
+        self.add_bus(route, capacity, false, false, None)
     }
 
-    pub fn new_bus(&mut self, route: &[&Arc<City>]) {
-        let route = route.iter().map(|&city| city.clone()).collect();
-        self.valid_route(&route);
-        let bus = Arc::new(Bus::new(route, self.next_bus_id));
+    /// Adds a bus that keeps cycling its route, returning from the last stop to the first one.
+    /// It stops at every city of the route, even when nobody is waiting there.
+    pub fn new_looping_bus(&mut self, route: &[&Arc<City>]) -> Result<u32, String> {
+        self.add_bus(route, u32::MAX, true, false, None)
+    }
+
+    /// Adds a shuttle bus that turns around at either end of its route and drives it back.
+    pub fn new_reversing_bus(&mut self, route: &[&Arc<City>]) -> Result<u32, String> {
+        self.add_bus(route, u32::MAX, false, true, None)
+    }
+
+    /// Adds a bus known under the given name, e.g. "Line 5".
+    pub fn new_named_bus(&mut self, name: &str, route: &[&Arc<City>]) -> Result<u32, String> {
+        self.add_bus(route, u32::MAX, false, false, Some(name.to_string()))
+    }
+
+    /// Finds the bus with the given name, the first added one if several share it.
+    pub fn bus_by_name(&self, name: &str) -> Option<Arc<Bus>> {
+        self.buses.iter().find(|bus| bus.name() == Some(name)).cloned()
+    }
+
+    fn add_bus(&mut self, route: &[&Arc<City>], capacity: u32, looping: bool, reversing: bool, name: Option<String>) -> Result<u32, String> {
+        let route: Vec<Arc<City>> = route.iter().map(|&city| city.clone()).collect();
+        self.valid_route(&route, looping)?;
+        let bus = Arc::new(Bus {
+            name,
+            ..Bus::new(route, self.next_bus_id, capacity, self.current_time, looping, reversing)
+        });
         self.buses.push(bus.clone());
         self.next_bus_id += 1;
         let first_event = Event {
@@ -195,95 +500,299 @@ impl Simulation {
             got_on_count: 0,
         };
         self.add_event(Arc::new(first_event), self.current_time);
+        Ok(bus.get_id())
+    }
+
+    /// Starts the simulation over, keeping the cities, roads and buses but no waiting people.
+    pub fn reset(&mut self) {
+        self.event_queue.clear();
+        self.waiting_people.clear();
+        self.passenger_groups.clear();
+        self.current_time = 0;
+        self.total_wait_time = 0;
+        self.total_abandoned = 0;
+        self.total_boarded = 0;
+        self.total_delivered = 0;
+        for bus in self.buses.clone() {
+            bus.reset();
+            let first_event = Event {
+                bus: bus.clone(),
+                city: bus.current_stop(),
+                got_off_count: 0,
+                got_on_count: 0,
+            };
+            self.add_event(Arc::new(first_event), bus.departure_time);
+        }
+    }
+
+    /// Returns when the given bus reaches the city, `None` for unknown buses or cities it no longer visits.
+    pub fn arrival_time(&self, bus_id: u32, city: &Arc<City>) -> Option<u32> {
+        let bus = self.buses.iter().find(|bus| bus.get_id() == bus_id)?;
+        bus.arrival_time(&self.roads, city)
     }
 
-    pub fn add_people(&mut self, from: &Arc<City>, to: &Arc<City>, count: u32) {
+    /// Lists the stops the given bus has yet to reach at the current time, starting with the one it stands at
+    /// or drives to, `None` for unknown buses.
+    pub fn remaining_route(&self, bus_id: u32) -> Option<Vec<Arc<City>>> {
+        let bus = self.buses.iter().find(|bus| bus.get_id() == bus_id)?;
+        let route = bus.route.lock().unwrap().iter().cloned().collect();
+        Some(route)
+    }
+
+    /// Counts the passengers aboard the given bus right now, an unknown bus carries nobody.
+    pub fn occupancy(&self, bus_id: u32) -> u32 {
+        self.buses.iter().find(|bus| bus.get_id() == bus_id).map_or(0, |bus| bus.occupancy())
+    }
+
+    /// Adds people waiting to travel between two cities of the simulation, or describes which city is unknown.
+    pub fn add_people(&mut self, from: &Arc<City>, to: &Arc<City>, count: u32) -> Result<(), String> {
+        self.add_people_with_patience(from, to, count, u32::MAX)
+    }
+
+    /// Adds people who give up and leave once they have waited longer than `patience` time units.
+    pub fn add_people_with_patience(&mut self, from: &Arc<City>, to: &Arc<City>, count: u32, patience: u32) -> Result<(), String> {
+        // Cities are told apart by identity, a city of another simulation with the same name is unknown as well
+        if let Some(unknown) = [from, to].into_iter().find(|&city| !self.cities.iter().any(|known| Arc::ptr_eq(known, city))) {
+            return Err(format!("Invalid passengers: {} is not a city of this simulation.", unknown.name()));
+        }
+
         // Retrieve or insert a new inner hashmap for the 'from' city
-        let destination_counts = self.waiting_people.entry(from.clone()).or_insert_with(HashMap::new);
+        let destination_counts = self.waiting_people.entry(from.clone()).or_default();
 
         // Add the number of people to the count for the destination city
         // If the destination city is not already in the map, it's inserted with the count
         *destination_counts.entry(to.clone()).or_insert(0) += count;
+
+        self.passenger_groups.entry((from.clone(), to.clone())).or_default().push_back(PassengerGroup {
+            count,
+            waiting_since: self.current_time,
+            patience,
+        });
+        Ok(())
+    }
+
+    // Sends home everybody who has been waiting for longer than their patience allows
+    fn abandon_impatient(&mut self, current_time: u32) {
+        for ((from, to), groups) in self.passenger_groups.iter_mut() {
+            let mut abandoned = 0;
+            groups.retain(|group| {
+                let gives_up = current_time - group.waiting_since > group.patience;
+                if gives_up {
+                    abandoned += group.count;
+                }
+                !gives_up
+            });
+            if abandoned > 0 {
+                *self.waiting_people.get_mut(from).unwrap().get_mut(to).unwrap() -= abandoned;
+                self.total_abandoned += abandoned;
+            }
+        }
+    }
+
+    // Takes the given number of the longest waiting people off the connection and adds up how long they waited
+    fn record_boarding(&mut self, from: &Arc<City>, to: &Arc<City>, mut boarding: u32, current_time: u32) {
+        let groups = self.passenger_groups.entry((from.clone(), to.clone())).or_default();
+        while boarding > 0 {
+            let Some(group) = groups.front_mut() else {
+                break;
+            };
+            let count = group.count.min(boarding);
+            self.total_wait_time += u64::from(count) * u64::from(current_time - group.waiting_since);
+            group.count -= count;
+            boarding -= count;
+            if group.count == 0 {
+                groups.pop_front();
+            }
+        }
+    }
+
+    /// Average time the people who boarded a bus spent waiting for it, people still waiting are not included.
+    pub fn average_wait_time(&self) -> f64 {
+        if self.total_boarded == 0 {
+            return 0.0;
+        }
+        self.total_wait_time as f64 / f64::from(self.total_boarded)
+    }
+
+    /// Counts everybody waiting at the given city regardless of where they are heading.
+    pub fn waiting_at(&self, city: &Arc<City>) -> u32 {
+        self.waiting_people.get(city).map_or(0, |destinations| destinations.values().sum())
+    }
+
+    /// Counts the people waiting at one city to get to another.
+    pub fn waiting_between(&self, from: &Arc<City>, to: &Arc<City>) -> u32 {
+        self.waiting_people.get(from).and_then(|destinations| destinations.get(to)).copied().unwrap_or(0)
+    }
+
+    // Lists the people waiting at the stop of the event for the further stops of the bus, the nearest stop first
+    fn waiting_along_route(&self, event: &Event) -> Vec<(Arc<City>, u32)> {
+        let Some(waiting) = self.waiting_people.get(&event.city) else {
+            return Vec::new();
+        };
+        let mut destinations: Vec<(Arc<City>, u32)> = Vec::new();
+        for stop in event.bus.route.lock().unwrap().iter().skip(1) {
+            if let Some(&count) = waiting.get(stop) {
+                if !destinations.iter().any(|(destination, _)| Arc::ptr_eq(destination, stop)) {
+                    destinations.push((stop.clone(), count));
+                }
+            }
+        }
+        destinations
     }
 
     fn process_waiting_people(&mut self, event: Arc<Event>, current_time: u32) -> Arc<Event> {
-        let destinations = self.waiting_people.get(&event.city).cloned();
+        // Seats are given out in the order of the stops, so who boards a full bus does not depend on hashing
+        let destinations = self.waiting_along_route(&event);
         let mut event = Arc::try_unwrap(event).unwrap_or_else(|e| (*e).clone()); // Try to unwrap Arc, or clone the content
+        event.bus.alight(event.got_off_count);
+        self.total_delivered += event.got_off_count;
 
-        if let Some(destinations) = destinations {
-            for (destination, people_waiting) in destinations.iter() {
-                if *people_waiting > 0 && event.bus.is_upcoming_stop(destination.clone()) {
-                    let travel_time = event.bus.calculate_travel_time(&self.roads, destination.clone(), current_time);
-                    
-                    let mut bus_events = self.event_queue.entry(travel_time).or_insert_with(BTreeMap::new);
-                    let existed_event = bus_events.entry(event.bus.get_id()).or_insert_with(|| 
-                        Arc::new(Event {
-                            bus: event.bus.clone(),
-                            city: destination.clone(),
-                            got_off_count: 0,
-                            got_on_count: 0,
-                        })
-                    );
-
-                    let mut existed_event = Arc::make_mut(existed_event);
-                    existed_event.got_off_count += *people_waiting;
-                    event.got_on_count += *people_waiting;
-                    
-                    // Reset the waiting count to 0
-                    let city_waiting_people = self.waiting_people.get_mut(&event.city).unwrap();
-                    *city_waiting_people.get_mut(destination).unwrap() = 0;
-                }
+        for (destination, people_waiting) in destinations.iter() {
+            // Only as many people as there are free seats can get on, the rest keeps waiting
+            let boarding = (*people_waiting).min(event.bus.free_seats());
+            if boarding > 0 && event.bus.is_upcoming_stop(destination.clone()) {
+                let travel_time = event.bus.calculate_travel_time(&self.roads, destination.clone(), current_time);
+                
+                let bus_events = self.event_queue.entry(travel_time).or_default();
+                let existed_event = bus_events.entry(event.bus.get_id()).or_insert_with(|| 
+                    Arc::new(Event {
+                        bus: event.bus.clone(),
+                        city: destination.clone(),
+                        got_off_count: 0,
+                        got_on_count: 0,
+                    })
+                );
+
+                // Every road takes at least one time unit, so the stops of one bus never share a time
+                // and an event already scheduled for this bus and time is the one at the destination
+                debug_assert!(Arc::ptr_eq(&existed_event.city, destination));
+
+                // Scheduled events are referenced only from the queue, so this updates the queued event in place
+                // and every boarding person is counted once at each end of the ride
+                let existed_event = Arc::make_mut(existed_event);
+                existed_event.got_off_count += boarding;
+                event.got_on_count += boarding;
+                event.bus.board(boarding);
+                self.total_boarded += boarding;
+                self.record_boarding(&event.city, destination, boarding, current_time);
+                
+                // Leave only the people who did not fit into the bus waiting
+                let city_waiting_people = self.waiting_people.get_mut(&event.city).unwrap();
+                *city_waiting_people.get_mut(destination).unwrap() -= boarding;
             }
         }
 
         Arc::new(event)
     }
 
+    pub fn stats(&self) -> Stats {
+        Stats {
+            total_boarded: self.total_boarded,
+            total_delivered: self.total_delivered,
+            people_still_waiting: self.waiting_people.values().flat_map(|destinations| destinations.values()).sum(),
+            total_abandoned: self.total_abandoned,
+        }
+    }
+
+    // Removes the event of the lowest bus id scheduled for the given time from the queue
+    fn take_next_event(&mut self, time: u32) -> Option<Arc<Event>> {
+        let bus_events = self.event_queue.get_mut(&time)?;
+        let (_, event) = bus_events.pop_first()?;
+        if bus_events.is_empty() {
+            self.event_queue.remove(&time);
+        }
+        Some(event)
+    }
+
+    // Makes the bus of the event stop at the next city of its route, if it does not stop there already
+    fn schedule_next_stop(&mut self, event: &Event, current_time: u32) {
+        let next_stop = event.bus.current_stop();
+        let Some(travel_time) = road_travel_time(&self.roads, &event.city, &next_stop) else {
+            return;
+        };
+        let bus_events = self.event_queue.entry(current_time + travel_time).or_default();
+        bus_events.entry(event.bus.get_id()).or_insert_with(|| Arc::new(Event {
+            bus: event.bus.clone(),
+            city: next_stop,
+            got_off_count: 0,
+            got_on_count: 0,
+        }));
+    }
+
+    /// Returns the time of the earliest scheduled event, `None` if nothing is scheduled anymore.
+    pub fn peek_next_event_time(&self) -> Option<u32> {
+        self.event_queue.keys().next().copied()
+    }
+
+    /// Jumps to the next scheduled event time and processes only that time slot.
+    pub fn step(&mut self) -> Vec<Arc<Event>> {
+        match self.peek_next_event_time() {
+            Some(time) => {
+                self.current_time = self.current_time.max(time);
+                self.execute(1)
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Registers a callback that sees every event right after it is processed, in the order of processing.
+    pub fn set_event_observer(&mut self, f: EventObserver) {
+        self.event_observer = Some(f);
+    }
+
     pub fn execute(&mut self, time_units_count: u32) -> Vec<Arc<Event>> {
         let mut events = Vec::new();
         let end_time = self.current_time + time_units_count; // Calculate end time once
 
         for current_time in self.current_time..end_time {
-            if let Some(bus_events) = self.event_queue.get_mut(&current_time) {
-                let cloned_events: Vec<_> = bus_events.values().cloned().collect(); // Clone the bus events
-                
-                for event in cloned_events {
-                    let processed_event = self.process_waiting_people(event, current_time);
-                    processed_event.bus.move_to_next();
-                    //if current_time == end_time - 1 {
-                        events.push(processed_event);
-                    //}
+            self.abandon_impatient(current_time);
+            // Events of one time slot are processed in ascending bus id order. Events scheduled for
+            // the current time while processing join the slot and are handled within the same step.
+            // Within an event the seats go to the people heading to the nearest stop first, so a rerun
+            // of the same scenario gives the very same events.
+            while let Some(event) = self.take_next_event(current_time) {
+                event.bus.arrive_at(&event.city);
+                let processed_event = self.process_waiting_people(event, current_time);
+                processed_event.bus.move_to_next();
+                // A looping bus stops everywhere even with nobody aboard, so people who come later are picked up
+                if processed_event.bus.looping {
+                    self.schedule_next_stop(&processed_event, current_time);
+                }
+                if let Some(observer) = self.event_observer.as_mut() {
+                    observer(&processed_event);
                 }
+                //if current_time == end_time - 1 {
+                    events.push(processed_event);
+                //}
             }
         }
 
         self.current_time += time_units_count; // Update the current time of the simulation
+        for bus in &self.buses {
+            bus.catch_up(&self.roads, self.current_time);
+        }
 
         events
     }
     
 }
 
-fn main() {
-    println!("Hello, world!");
+fn main() -> Result<(), String> {
     let mut simulation = Simulation::new();
     let pls = simulation.new_city("Plzen");
     let prg = simulation.new_city("Prague");
     let brn = simulation.new_city("Brno");
     let ust = simulation.new_city("Usti");
-    let d1 = simulation.new_road(&pls, &prg, 90);
-    let d2 = simulation.new_road(&prg, &brn, 120);
-    let d3 = simulation.new_road(&prg, &ust, 80);
-    let d4 = simulation.new_road(&pls, &ust, 110);
-    simulation.new_bus(&[&pls, &prg, &brn]);
-    simulation.new_bus(&[&prg, &pls, &ust]);
-    simulation.add_people(&prg, &brn, 50);
-    simulation.add_people(&prg, &ust, 50);
-    simulation.add_people(&pls, &ust, 50);
-    simulation.add_people(&pls, &prg, 10);
-    //simulation.add_people(&brn, &prg, 50);
-    //simulation.test_calc(brn.clone());
-    //simulation.test_calc(prg.clone());
+    simulation.new_road(&pls, &prg, 90);
+    simulation.new_road(&prg, &brn, 120);
+    simulation.new_road(&prg, &ust, 80);
+    simulation.new_road(&pls, &ust, 110);
+    simulation.new_bus(&[&pls, &prg, &brn])?;
+    simulation.new_bus(&[&prg, &pls, &ust])?;
+    simulation.add_people(&prg, &brn, 50)?;
+    simulation.add_people(&prg, &ust, 50)?;
+    simulation.add_people(&pls, &ust, 50)?;
+    simulation.add_people(&pls, &prg, 10)?;
     for event in simulation.execute(270) {
         let name = event.city().name();
         let people_got_off = event.got_off();
@@ -296,5 +805,563 @@ fn main() {
         let people_got_on = event.got_on();
         println!("At {}, {} people got off and {} people got on at {}", simulation.current_time, people_got_off, people_got_on, name);
     }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The network of the demo in main: Plzen, Prague, Brno and Usti
+    fn sample_network() -> (Simulation, [Arc<City>; 4]) {
+        let mut simulation = Simulation::new();
+        let pls = simulation.new_city("Plzen");
+        let prg = simulation.new_city("Prague");
+        let brn = simulation.new_city("Brno");
+        let ust = simulation.new_city("Usti");
+        simulation.new_road(&pls, &prg, 90);
+        simulation.new_road(&prg, &brn, 120);
+        simulation.new_road(&prg, &ust, 80);
+        simulation.new_road(&pls, &ust, 110);
+        (simulation, [pls, prg, brn, ust])
+    }
+
+    #[test]
+    fn finds_shortest_paths() {
+        let (mut simulation, [pls, _, brn, ust]) = sample_network();
+        let (time, path) = simulation.shortest_path(&ust, &brn).expect("Usti and Brno are connected");
+        assert_eq!(time, 200);
+        assert_eq!(path.iter().map(|city| city.name()).collect::<Vec<_>>(), ["Usti", "Prague", "Brno"]);
+        let (time, path) = simulation.shortest_path(&pls, &pls).expect("a city is connected to itself");
+        assert_eq!((time, path.len()), (0, 1));
+        let isolated = simulation.new_city("Ostrava");
+        assert!(simulation.shortest_path(&pls, &isolated).is_none());
+    }
+
+    #[test]
+    fn checks_connectivity() {
+        let (mut simulation, _) = sample_network();
+        assert!(simulation.is_connected());
+        // Only the cities on a road make up the network
+        simulation.new_city("Ostrava");
+        assert!(simulation.is_connected());
+        let mut split_network = Simulation::new();
+        let ost = split_network.new_city("Ostrava");
+        let olo = split_network.new_city("Olomouc");
+        let zln = split_network.new_city("Zlin");
+        let jih = split_network.new_city("Jihlava");
+        split_network.new_road(&ost, &olo, 70);
+        split_network.new_road(&zln, &jih, 150);
+        assert!(!split_network.is_connected());
+        split_network.new_road(&olo, &zln, 60);
+        assert!(split_network.is_connected());
+        assert!(Simulation::new().is_connected());
+    }
+
+    #[test]
+    fn lists_the_roads_of_every_city() {
+        let (mut simulation, [pls, prg, brn, _]) = sample_network();
+        let isolated = simulation.new_city("Ostrava");
+        let graph = simulation.road_graph();
+        let mut prague_neighbors: Vec<(String, u32)> = graph[&prg].iter().map(|(city, time)| (city.name(), *time)).collect();
+        prague_neighbors.sort();
+        assert_eq!(prague_neighbors, [("Brno".to_string(), 120), ("Plzen".to_string(), 90), ("Usti".to_string(), 80)]);
+        // Every road shows up at both of its ends
+        assert!(graph[&pls].iter().any(|(city, time)| Arc::ptr_eq(city, &prg) && *time == 90));
+        assert_eq!(graph[&brn].len(), 1);
+        assert!(!graph.contains_key(&isolated));
+        assert_eq!(graph.len(), 4);
+    }
+
+    #[test]
+    fn tells_arrival_times() -> Result<(), String> {
+        let (mut simulation, [pls, prg, brn, ust]) = sample_network();
+        simulation.new_bus(&[&pls, &prg, &brn])?;
+        simulation.new_bus(&[&prg, &pls, &ust])?;
+        assert_eq!(simulation.arrival_time(0, &pls), Some(0));
+        assert_eq!(simulation.arrival_time(0, &brn), Some(90 + 120));
+        assert_eq!(simulation.arrival_time(1, &ust), Some(90 + 110));
+        assert_eq!(simulation.arrival_time(1, &brn), None);
+        assert_eq!(simulation.arrival_time(2, &brn), None);
+        Ok(())
+    }
+
+    // A bus from Prague through Brno to Olomouc, nobody gets on or off in Brno
+    fn bus_through_brno() -> Result<(Simulation, [Arc<City>; 3]), String> {
+        let mut simulation = Simulation::new();
+        let prg = simulation.new_city("Prague");
+        let brn = simulation.new_city("Brno");
+        let olo = simulation.new_city("Olomouc");
+        simulation.new_road(&prg, &brn, 120);
+        simulation.new_road(&brn, &olo, 60);
+        simulation.new_bus(&[&prg, &brn, &olo])?;
+        simulation.add_people(&prg, &olo, 3)?;
+        Ok((simulation, [prg, brn, olo]))
+    }
+
+    #[test]
+    fn forgets_arrivals_in_the_past() -> Result<(), String> {
+        let (mut simulation, [prg, brn, olo]) = bus_through_brno()?;
+        simulation.execute(120);
+        assert_eq!(simulation.arrival_time(0, &brn), Some(120));
+        simulation.execute(30);
+        assert_eq!(simulation.arrival_time(0, &prg), None);
+        assert_eq!(simulation.arrival_time(0, &brn), None);
+        assert_eq!(simulation.arrival_time(0, &olo), Some(180));
+        simulation.execute(100);
+        assert_eq!(simulation.arrival_time(0, &olo), None);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_routes() {
+        let (mut simulation, [pls, prg, brn, ust]) = sample_network();
+        assert_eq!(simulation.new_bus(&[&prg]), Err("Invalid bus route: A bus must have at least two stops.".to_string()));
+        assert_eq!(simulation.new_bus(&[&pls, &brn, &ust]), Err("Invalid bus route: There is no road between Plzen and Brno.".to_string()));
+        assert_eq!(simulation.new_bus(&[&prg, &ust, &brn]), Err("Invalid bus route: There is no road between Usti and Brno.".to_string()));
+        // Rejected routes do not use up bus ids
+        assert_eq!(simulation.new_bus(&[&pls, &prg, &brn]), Ok(0));
+    }
 
+    #[test]
+    fn merges_parallel_roads() {
+        let mut simulation = Simulation::new();
+        let prg = simulation.new_city("Prague");
+        let brn = simulation.new_city("Brno");
+        let road = simulation.new_road(&prg, &brn, 120);
+        assert!(Arc::ptr_eq(&simulation.new_road(&brn, &prg, 150), &road));
+        assert_eq!(simulation.roads.len(), 1);
+        let faster_road = simulation.new_road(&prg, &brn, 100);
+        assert_eq!(faster_road.travel_time, 100);
+        assert_eq!(simulation.roads.len(), 1);
+        simulation.roads.insert(Arc::new(Road { travel_time: 300, point_a: brn.clone(), point_b: prg.clone() }));
+        let parallel_bus = simulation.new_bus(&[&prg, &brn]).expect("Prague and Brno are connected");
+        assert_eq!(simulation.arrival_time(parallel_bus, &brn), Some(100));
+        assert_eq!(simulation.shortest_path(&prg, &brn).map(|(time, _)| time), Some(100));
+    }
+
+    #[test]
+    fn limits_the_bus_capacity() -> Result<(), String> {
+        let mut simulation = Simulation::new();
+        let prg = simulation.new_city("Prague");
+        let brn = simulation.new_city("Brno");
+        simulation.new_road(&prg, &brn, 120);
+        simulation.new_bus_with_capacity(&[&prg, &brn], 10).expect("Prague and Brno are connected");
+        simulation.add_people(&prg, &brn, 50)?;
+        let events = simulation.execute(1);
+        assert_eq!(events[0].got_on(), 10);
+        assert_eq!(simulation.waiting_between(&prg, &brn), 40);
+        Ok(())
+    }
+
+    #[test]
+    fn fills_the_seats_in_the_order_of_the_stops() -> Result<(), String> {
+        // Every simulation hashes differently, so a few runs would catch an order picked by hashing
+        for _ in 0..20 {
+            let mut simulation = Simulation::new();
+            let prg = simulation.new_city("Prague");
+            let brn = simulation.new_city("Brno");
+            let olo = simulation.new_city("Olomouc");
+            simulation.new_road(&prg, &brn, 120);
+            simulation.new_road(&brn, &olo, 60);
+            simulation.new_bus_with_capacity(&[&prg, &brn, &olo], 5).expect("the route is connected");
+            simulation.add_people(&prg, &olo, 5)?;
+            simulation.add_people(&prg, &brn, 5)?;
+            simulation.execute(1);
+            assert_eq!((simulation.waiting_between(&prg, &brn), simulation.waiting_between(&prg, &olo)), (0, 5));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn boards_the_same_way_in_every_run() -> Result<(), String> {
+        let run = || -> Result<Vec<(u32, String, u32, u32)>, String> {
+            let mut simulation = Simulation::new();
+            let prg = simulation.new_city("Prague");
+            let brn = simulation.new_city("Brno");
+            let olo = simulation.new_city("Olomouc");
+            simulation.new_road(&prg, &brn, 120);
+            simulation.new_road(&brn, &olo, 60);
+            simulation.new_road(&prg, &olo, 150);
+            simulation.new_bus_with_capacity(&[&prg, &brn, &olo], 4).expect("the route is connected");
+            simulation.new_bus_with_capacity(&[&prg, &olo], 4).expect("the route is connected");
+            simulation.add_people(&prg, &olo, 6)?;
+            simulation.add_people(&prg, &brn, 3)?;
+            let events = describe(simulation.execute(200));
+            assert_eq!(simulation.waiting_between(&prg, &olo), 1);
+            Ok(events)
+        };
+        let expected = [
+            (0, "Prague".to_string(), 0, 4),
+            (1, "Prague".to_string(), 0, 4),
+            (0, "Brno".to_string(), 3, 0),
+            (1, "Olomouc".to_string(), 4, 0),
+            (0, "Olomouc".to_string(), 1, 0),
+        ];
+        for _ in 0..20 {
+            assert_eq!(run()?, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn runs_looping_buses() -> Result<(), String> {
+        let mut simulation = Simulation::new();
+        let prg = simulation.new_city("Prague");
+        let brn = simulation.new_city("Brno");
+        let olo = simulation.new_city("Olomouc");
+        simulation.new_road(&prg, &brn, 120);
+        simulation.new_road(&brn, &olo, 60);
+        assert!(simulation.new_looping_bus(&[&prg, &brn, &olo]).is_err());
+        simulation.new_road(&olo, &prg, 180);
+        let looping_bus = simulation.new_looping_bus(&[&prg, &brn, &olo]).expect("the loop is closed");
+        simulation.add_people(&prg, &brn, 5)?;
+        simulation.add_people(&brn, &olo, 5)?;
+        simulation.add_people(&olo, &prg, 5)?;
+        let prague_visits: Vec<u32> = (0..400)
+            .filter(|_| simulation.execute(1).iter().any(|event| Arc::ptr_eq(event.city(), &prg)))
+            .collect();
+        assert_eq!(prague_visits, [0, 360]);
+        assert_eq!(simulation.arrival_time(looping_bus, &brn), Some(360 + 120));
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_looping_without_passengers() -> Result<(), String> {
+        let mut simulation = Simulation::new();
+        let prg = simulation.new_city("Prague");
+        let brn = simulation.new_city("Brno");
+        simulation.new_road(&prg, &brn, 20);
+        let looping_bus = simulation.new_looping_bus(&[&prg, &brn]).expect("the loop is closed");
+        assert_eq!(simulation.execute(100).len(), 5);
+        assert_eq!(simulation.arrival_time(looping_bus, &brn), Some(100));
+        assert_eq!(simulation.arrival_time(looping_bus, &prg), Some(120));
+        simulation.add_people(&prg, &brn, 3)?;
+        let events = describe(simulation.execute(1000));
+        assert_eq!(events.len(), 50);
+        assert!(events.contains(&(looping_bus, "Prague".to_string(), 0, 3)));
+        assert!(events.contains(&(looping_bus, "Brno".to_string(), 3, 0)));
+        assert_eq!(simulation.stats().total_delivered, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn runs_reversing_buses() -> Result<(), String> {
+        let mut simulation = Simulation::new();
+        let prg = simulation.new_city("Prague");
+        let brn = simulation.new_city("Brno");
+        let olo = simulation.new_city("Olomouc");
+        simulation.new_road(&prg, &brn, 120);
+        simulation.new_road(&brn, &olo, 60);
+        let shuttle = simulation.new_reversing_bus(&[&prg, &brn, &olo]).expect("the route is connected");
+        simulation.add_people(&prg, &olo, 4)?;
+        simulation.add_people(&olo, &prg, 6)?;
+        let events = simulation.execute(181);
+        let terminus = events.iter().find(|event| Arc::ptr_eq(event.city(), &olo)).expect("the shuttle reaches Olomouc");
+        assert_eq!((terminus.got_off(), terminus.got_on()), (4, 6));
+        assert_eq!(simulation.arrival_time(shuttle, &prg), Some(180 + 180));
+        let events = simulation.execute(180);
+        assert!(events.iter().any(|event| Arc::ptr_eq(event.city(), &prg) && event.got_off() == 6));
+        Ok(())
+    }
+
+    // Two small buses from Prague to Brno, both departing at once, with more people than one of them can take
+    fn two_buses_to_brno() -> Result<(Simulation, Arc<City>, Arc<City>), String> {
+        let mut simulation = Simulation::new();
+        let prg = simulation.new_city("Prague");
+        let brn = simulation.new_city("Brno");
+        simulation.new_road(&prg, &brn, 120);
+        simulation.new_bus_with_capacity(&[&prg, &brn], 10)?;
+        simulation.new_bus_with_capacity(&[&prg, &brn], 10)?;
+        simulation.add_people(&prg, &brn, 15)?;
+        simulation.add_people(&brn, &prg, 3)?;
+        Ok((simulation, prg, brn))
+    }
+
+    #[test]
+    fn processes_simultaneous_events_by_bus_id() -> Result<(), String> {
+        let (mut simulation, _, _) = two_buses_to_brno()?;
+        let events: Vec<_> = simulation.execute(200).iter()
+            .map(|event| (event.bus_id(), event.city().name(), event.got_off(), event.got_on()))
+            .collect();
+        assert_eq!(events, [
+            (0, "Prague".to_string(), 0, 10),
+            (1, "Prague".to_string(), 0, 5),
+            (0, "Brno".to_string(), 10, 0),
+            (1, "Brno".to_string(), 5, 0),
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn summarizes_the_statistics() -> Result<(), String> {
+        let (mut simulation, _, _) = two_buses_to_brno()?;
+        assert_eq!(simulation.stats(), Stats { total_boarded: 0, total_delivered: 0, people_still_waiting: 18, total_abandoned: 0 });
+        simulation.execute(100);
+        assert_eq!(simulation.stats(), Stats { total_boarded: 15, total_delivered: 0, people_still_waiting: 3, total_abandoned: 0 });
+        simulation.execute(100);
+        assert_eq!(simulation.stats(), Stats { total_boarded: 15, total_delivered: 15, people_still_waiting: 3, total_abandoned: 0 });
+        Ok(())
+    }
+
+    #[test]
+    fn counts_waiting_people() -> Result<(), String> {
+        let (mut simulation, prg, brn) = two_buses_to_brno()?;
+        assert_eq!((simulation.waiting_at(&prg), simulation.waiting_at(&brn)), (15, 3));
+        assert_eq!(simulation.waiting_between(&prg, &brn), 15);
+        assert_eq!(simulation.waiting_between(&brn, &prg), 3);
+        assert_eq!(simulation.waiting_between(&prg, &prg), 0);
+        let ost = simulation.new_city("Ostrava");
+        assert_eq!(simulation.waiting_at(&ost), 0);
+        assert_eq!(simulation.waiting_between(&ost, &prg), 0);
+        simulation.execute(1);
+        assert_eq!((simulation.waiting_at(&prg), simulation.waiting_at(&brn)), (0, 3));
+        Ok(())
+    }
+
+    #[test]
+    fn drops_off_everybody_on_the_way() -> Result<(), String> {
+        let mut simulation = Simulation::new();
+        let prg = simulation.new_city("Prague");
+        let brn = simulation.new_city("Brno");
+        let olo = simulation.new_city("Olomouc");
+        simulation.new_road(&prg, &brn, 120);
+        simulation.new_road(&brn, &olo, 60);
+        simulation.new_bus_with_capacity(&[&prg, &brn, &olo], 10).expect("the route is connected");
+        simulation.add_people(&prg, &brn, 3)?;
+        simulation.add_people(&prg, &olo, 4)?;
+        simulation.add_people(&brn, &olo, 5)?;
+        let events = simulation.execute(500);
+        let got_on: u32 = events.iter().map(|event| event.got_on()).sum();
+        let got_off: u32 = events.iter().map(|event| event.got_off()).sum();
+        assert_eq!((got_on, got_off), (12, 12));
+        assert_eq!(simulation.stats(), Stats { total_boarded: 12, total_delivered: 12, people_still_waiting: 0, total_abandoned: 0 });
+        Ok(())
+    }
+
+    #[test]
+    fn averages_wait_times() -> Result<(), String> {
+        let mut simulation = Simulation::new();
+        let prg = simulation.new_city("Prague");
+        let brn = simulation.new_city("Brno");
+        let olo = simulation.new_city("Olomouc");
+        simulation.new_road(&prg, &brn, 120);
+        simulation.new_road(&brn, &olo, 60);
+        simulation.new_bus(&[&prg, &brn, &olo]).expect("the route is connected");
+        simulation.add_people(&prg, &brn, 2)?;
+        simulation.execute(50);
+        simulation.add_people(&brn, &olo, 3)?;
+        simulation.execute(100);
+        simulation.add_people(&brn, &olo, 1)?;
+        assert_eq!(simulation.average_wait_time(), (2.0 * 0.0 + 3.0 * 70.0) / 5.0);
+        Ok(())
+    }
+
+    #[test]
+    fn sends_impatient_people_home() -> Result<(), String> {
+        let mut simulation = Simulation::new();
+        let prg = simulation.new_city("Prague");
+        let brn = simulation.new_city("Brno");
+        let olo = simulation.new_city("Olomouc");
+        simulation.new_road(&prg, &brn, 120);
+        simulation.new_road(&brn, &olo, 60);
+        simulation.new_bus(&[&prg, &brn, &olo]).expect("the route is connected");
+        simulation.add_people(&prg, &brn, 1)?;
+        simulation.add_people_with_patience(&brn, &olo, 5, 60)?;
+        simulation.add_people_with_patience(&brn, &olo, 2, 200)?;
+        simulation.execute(61);
+        assert_eq!(simulation.waiting_between(&brn, &olo), 7);
+        let events = simulation.execute(200);
+        assert!(events.iter().any(|event| Arc::ptr_eq(event.city(), &brn) && event.got_on() == 2));
+        assert_eq!(simulation.stats(), Stats { total_boarded: 3, total_delivered: 3, people_still_waiting: 0, total_abandoned: 5 });
+        Ok(())
+    }
+
+    fn add_travellers(simulation: &mut Simulation, [prg, brn, olo]: &[Arc<City>; 3]) -> Result<(), String> {
+        simulation.add_people(prg, olo, 4)?;
+        simulation.add_people(brn, olo, 2)?;
+        simulation.add_people(olo, prg, 3)
+    }
+
+    // Two buses going in the opposite directions between Prague and Olomouc with people waiting for both
+    fn two_way_line() -> (Simulation, [Arc<City>; 3]) {
+        let mut simulation = Simulation::new();
+        let prg = simulation.new_city("Prague");
+        let brn = simulation.new_city("Brno");
+        let olo = simulation.new_city("Olomouc");
+        simulation.new_road(&prg, &brn, 120);
+        simulation.new_road(&brn, &olo, 60);
+        simulation.new_bus(&[&prg, &brn, &olo]).expect("the route is connected");
+        simulation.new_bus(&[&olo, &brn, &prg]).expect("the route is connected");
+        let cities = [prg, brn, olo];
+        add_travellers(&mut simulation, &cities).expect("the cities belong to the simulation");
+        (simulation, cities)
+    }
+
+    fn describe(events: Vec<Arc<Event>>) -> Vec<(u32, String, u32, u32)> {
+        events.iter().map(|event| (event.bus_id(), event.city().name(), event.got_off(), event.got_on())).collect()
+    }
+
+    #[test]
+    fn reruns_identically_after_reset() -> Result<(), String> {
+        let (mut simulation, cities) = two_way_line();
+        let first_run = describe(simulation.execute(1000));
+        simulation.reset();
+        assert_eq!(simulation.stats(), Stats { total_boarded: 0, total_delivered: 0, people_still_waiting: 0, total_abandoned: 0 });
+        add_travellers(&mut simulation, &cities)?;
+        assert_eq!(describe(simulation.execute(1000)), first_run);
+        Ok(())
+    }
+
+    #[test]
+    fn reports_events_to_the_observer() {
+        let (mut observed, _) = two_way_line();
+        let observed_events = Arc::new(Mutex::new(Vec::new()));
+        let observer_events = observed_events.clone();
+        observed.set_event_observer(Box::new(move |event| {
+            observer_events.lock().unwrap().push((event.bus_id(), event.city().name(), event.got_off(), event.got_on()));
+        }));
+        let returned_events = describe(observed.execute(1000));
+        assert!(!returned_events.is_empty());
+        assert_eq!(*observed_events.lock().unwrap(), returned_events);
+    }
+
+    #[test]
+    fn steps_from_event_to_event() {
+        let (mut stepped, _) = two_way_line();
+        let mut stepped_events = Vec::new();
+        while stepped.peek_next_event_time().is_some() {
+            stepped_events.extend(describe(stepped.step()));
+        }
+        assert_eq!(stepped.peek_next_event_time(), None);
+        assert!(stepped.step().is_empty());
+        assert_eq!(stepped_events, describe(two_way_line().0.execute(1000)));
+    }
+
+    #[test]
+    fn rounds_zero_travel_times_up() -> Result<(), String> {
+        let mut simulation = Simulation::new();
+        let prg = simulation.new_city("Prague");
+        let brn = simulation.new_city("Brno");
+        let olo = simulation.new_city("Olomouc");
+        assert_eq!(simulation.new_road(&prg, &brn, 0).travel_time, 1);
+        simulation.new_road(&brn, &olo, 0);
+        simulation.new_bus(&[&prg, &brn, &olo]).expect("the route is connected");
+        simulation.add_people(&prg, &brn, 2)?;
+        simulation.add_people(&prg, &olo, 3)?;
+        let events = simulation.execute(10);
+        assert_eq!(events.len(), 3);
+        assert_eq!(simulation.stats().total_delivered, 5);
+        assert_eq!(simulation.peek_next_event_time(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_foreign_cities() -> Result<(), String> {
+        let mut simulation = Simulation::new();
+        let prg = simulation.new_city("Prague");
+        let brn = simulation.new_city("Brno");
+        let unknown = Simulation::new().new_city("Ostrava");
+        assert!(simulation.add_people(&unknown, &brn, 1).is_err());
+        assert_eq!(
+            simulation.add_people(&prg, &unknown, 1),
+            Err("Invalid passengers: Ostrava is not a city of this simulation.".to_string())
+        );
+        simulation.add_people(&prg, &brn, 1)?;
+        assert_eq!(simulation.stats().people_still_waiting, 1);
+        let prague_again = simulation.new_city("Prague");
+        assert!(Arc::ptr_eq(&prague_again, &prg));
+        simulation.add_people(&prague_again, &brn, 1)?;
+        assert_eq!(simulation.waiting_between(&prg, &brn), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn tracks_occupancy() -> Result<(), String> {
+        let mut simulation = Simulation::new();
+        let prg = simulation.new_city("Prague");
+        let brn = simulation.new_city("Brno");
+        let olo = simulation.new_city("Olomouc");
+        simulation.new_road(&prg, &brn, 120);
+        simulation.new_road(&brn, &olo, 60);
+        let bus = simulation.new_bus(&[&prg, &brn, &olo]).expect("the route is connected");
+        simulation.add_people(&prg, &brn, 5)?;
+        simulation.add_people(&prg, &olo, 2)?;
+        assert_eq!(simulation.occupancy(bus), 0);
+        simulation.execute(1);
+        assert_eq!(simulation.occupancy(bus), 7);
+        simulation.execute(119);
+        assert_eq!(simulation.occupancy(bus), 7);
+        simulation.execute(1);
+        assert_eq!(simulation.occupancy(bus), 2);
+        simulation.execute(60);
+        assert_eq!(simulation.occupancy(bus), 0);
+        assert_eq!(simulation.occupancy(42), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn drops_off_at_each_destination() -> Result<(), String> {
+        let mut simulation = Simulation::new();
+        let prg = simulation.new_city("Prague");
+        let brn = simulation.new_city("Brno");
+        let olo = simulation.new_city("Olomouc");
+        let ost = simulation.new_city("Ostrava");
+        simulation.new_road(&prg, &brn, 120);
+        simulation.new_road(&brn, &olo, 60);
+        simulation.new_road(&olo, &ost, 70);
+        simulation.new_bus(&[&prg, &brn, &olo, &ost]).expect("the route is connected");
+        simulation.add_people(&prg, &brn, 1)?;
+        simulation.add_people(&prg, &olo, 2)?;
+        simulation.add_people(&prg, &ost, 3)?;
+        let got_off: Vec<_> = simulation.execute(300).iter()
+            .filter(|event| event.got_off() > 0)
+            .map(|event| (event.city().name(), event.got_off()))
+            .collect();
+        assert_eq!(got_off, [("Brno".to_string(), 1), ("Olomouc".to_string(), 2), ("Ostrava".to_string(), 3)]);
+        Ok(())
+    }
+
+    #[test]
+    fn lists_the_remaining_route() {
+        let mut simulation = Simulation::new();
+        let prg = simulation.new_city("Prague");
+        let brn = simulation.new_city("Brno");
+        let olo = simulation.new_city("Olomouc");
+        simulation.new_road(&prg, &brn, 120);
+        simulation.new_road(&brn, &olo, 60);
+        let bus = simulation.new_bus(&[&prg, &brn, &olo]).expect("the route is connected");
+        let names = |route: Option<Vec<Arc<City>>>| route.map(|route| route.iter().map(|city| city.name()).collect::<Vec<_>>());
+        assert_eq!(names(simulation.remaining_route(bus)), Some(vec!["Prague".to_string(), "Brno".to_string(), "Olomouc".to_string()]));
+        simulation.buses[0].move_to_next();
+        assert_eq!(names(simulation.remaining_route(bus)), Some(vec!["Brno".to_string(), "Olomouc".to_string()]));
+        simulation.execute(500);
+        assert_eq!(simulation.remaining_route(bus).map(|route| route.len()), Some(0));
+        assert!(simulation.remaining_route(42).is_none());
+    }
+
+    #[test]
+    fn leaves_passed_stops_out_of_the_remaining_route() -> Result<(), String> {
+        let (mut simulation, _) = bus_through_brno()?;
+        let names = |route: Option<Vec<Arc<City>>>| route.map(|route| route.iter().map(|city| city.name()).collect::<Vec<_>>());
+        simulation.execute(120);
+        assert_eq!(names(simulation.remaining_route(0)), Some(vec!["Brno".to_string(), "Olomouc".to_string()]));
+        simulation.execute(30);
+        assert_eq!(names(simulation.remaining_route(0)), Some(vec!["Olomouc".to_string()]));
+        Ok(())
+    }
+
+    #[test]
+    fn finds_buses_by_name() {
+        let mut simulation = Simulation::new();
+        let prg = simulation.new_city("Prague");
+        let brn = simulation.new_city("Brno");
+        simulation.new_road(&prg, &brn, 120);
+        simulation.new_bus(&[&prg, &brn]).expect("Prague and Brno are connected");
+        let line = simulation.new_named_bus("Line 5", &[&brn, &prg]).expect("Prague and Brno are connected");
+        let bus = simulation.bus_by_name("Line 5").expect("the bus was named");
+        assert_eq!((bus.get_id(), bus.name()), (line, Some("Line 5")));
+        assert!(simulation.bus_by_name("Line 6").is_none());
+        assert!(simulation.new_named_bus("Line 6", &[&prg]).is_err());
+    }
 }
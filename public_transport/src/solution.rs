@@ -1,5 +1,9 @@
+// The demo in main uses only a part of the library, the rest is covered by the tests
+#![cfg_attr(not(test), allow(dead_code))]
+
 use std::sync::{Arc, Mutex};
-use std::collections::{HashSet, HashMap, VecDeque, BTreeMap};
+use std::collections::{HashSet, HashMap, VecDeque, BTreeMap, BinaryHeap};
+use std::cmp::Reverse;
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct City {
@@ -19,24 +23,52 @@ pub struct Road {
     point_b: Arc<City>,
 }
 
+impl Road {
+    fn connects(&self, a: &Arc<City>, b: &Arc<City>) -> bool {
+        (Arc::ptr_eq(&self.point_a, a) && Arc::ptr_eq(&self.point_b, b)) ||
+        (Arc::ptr_eq(&self.point_a, b) && Arc::ptr_eq(&self.point_b, a))
+    }
+}
+
+// Finds the travel time of the fastest road between two cities, if they are connected
+fn road_travel_time(roads: &HashSet<Arc<Road>>, a: &Arc<City>, b: &Arc<City>) -> Option<u32> {
+    roads.iter().filter(|road| road.connects(a, b)).map(|road| road.travel_time).min()
+}
+
 pub struct Bus {
     id: u32,
+    name: Option<String>, // how operators call the line, the id stays the key for scheduling
     route: Mutex<VecDeque<Arc<City>>>,
     upcoming_stops: Mutex<HashSet<Arc<City>>>,
+    total_route: VecDeque<Arc<City>>,
+    departure_time: u32, // when the bus stands at the first stop of its route
+    stops_passed: Mutex<usize>,
     time_people_getting_off: Mutex<BTreeMap<Arc<City>, u32>>,
     finished: Mutex<bool>,
+    capacity: u32,
+    occupancy: Mutex<u32>,
+    looping: bool, // returns from the last stop to the first one and starts over instead of finishing
+    reversing: bool, // drives the route backward from the last stop, then forward again, instead of finishing
 }
 
 impl Bus {
-    pub fn new(route: Vec<Arc<City>>, id: u32) -> Self {
+    pub fn new(route: Vec<Arc<City>>, id: u32, capacity: u32, departure_time: u32, looping: bool, reversing: bool) -> Self {
         let route_deque = VecDeque::from(route.to_vec());
         let upcoming_stops = Mutex::new(route.iter().cloned().collect());
         Bus {
             id,
+            name: None,
             route: Mutex::new(route_deque.clone()),
             upcoming_stops,
+            total_route: route_deque,
+            departure_time,
+            stops_passed: Mutex::new(0),
             time_people_getting_off: Mutex::new(BTreeMap::new()),
             finished: Mutex::new(false),
+            capacity,
+            occupancy: Mutex::new(0),
+            looping,
+            reversing,
         }
     }
 
@@ -44,14 +76,53 @@ impl Bus {
         self.id
     }
 
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
     pub fn is_upcoming_stop(&self, city: Arc<City>) -> bool {
         self.upcoming_stops.lock().unwrap().contains(&city) && city != self.current_stop()
     }
 
+    pub fn free_seats(&self) -> u32 {
+        self.capacity - *self.occupancy.lock().unwrap()
+    }
+
+    pub fn occupancy(&self) -> u32 {
+        *self.occupancy.lock().unwrap()
+    }
+
+    fn board(&self, count: u32) {
+        *self.occupancy.lock().unwrap() += count;
+    }
+
+    fn alight(&self, count: u32) {
+        let mut occupancy = self.occupancy.lock().unwrap();
+        *occupancy = occupancy.saturating_sub(count);
+    }
+
     fn current_stop(&self) -> Arc<City> {
         self.route.lock().unwrap().front().unwrap().clone()
     }
 
+    /// Puts the bus back to its first stop, empty and ready to depart again.
+    fn reset(&self) {
+        *self.route.lock().unwrap() = self.total_route.clone();
+        *self.upcoming_stops.lock().unwrap() = self.total_route.iter().cloned().collect();
+        *self.stops_passed.lock().unwrap() = 0;
+        self.time_people_getting_off.lock().unwrap().clear();
+        *self.finished.lock().unwrap() = false;
+        *self.occupancy.lock().unwrap() = 0;
+    }
+
+    /// Catches the route up with the city the bus has just reached.
+    fn arrive_at(&self, city: &Arc<City>) {
+        // Stops where nobody got on or off have no event, the bus passed them on the way here
+        while self.route.lock().unwrap().front().is_some_and(|stop| !Arc::ptr_eq(stop, city)) {
+            self.move_to_next();
+        }
+    }
+
     pub fn move_to_next(&self) {
         let mut finished = self.finished.lock().unwrap();
         if *finished {
@@ -61,7 +132,25 @@ impl Bus {
         let mut upcoming_stops = self.upcoming_stops.lock().unwrap();
 
         if let Some(next_city) = route.pop_front() {
-            upcoming_stops.remove(&next_city);
+            // The next visit of this city, if any, happens at a different time
+            self.time_people_getting_off.lock().unwrap().remove(&next_city);
+            *self.stops_passed.lock().unwrap() += 1;
+            if self.looping {
+                route.push_back(next_city);
+            } else {
+                upcoming_stops.remove(&next_city);
+            }
+            // At the terminus the return leg is appended, so people there can already board for it
+            if self.reversing && route.len() == 1 {
+                let terminus = route[0].clone();
+                let leg: Vec<Arc<City>> = if Arc::ptr_eq(&terminus, &self.total_route[0]) {
+                    self.total_route.iter().skip(1).cloned().collect()
+                } else {
+                    self.total_route.iter().rev().skip(1).cloned().collect()
+                };
+                route.extend(leg);
+                *upcoming_stops = route.iter().cloned().collect();
+            }
         } else {
             *finished = true;
         }
@@ -78,11 +167,8 @@ impl Bus {
         // Skipping the first city in the route as it's the current stop
         for city in self.route.lock().unwrap().iter().skip(1) {
             // Find the road between current_stop and the next city in the route
-            if let Some(road) = roads.iter().find(|road| {
-                (Arc::ptr_eq(&road.point_a, &current_stop) && Arc::ptr_eq(&road.point_b, city)) ||
-                (Arc::ptr_eq(&road.point_a, city) && Arc::ptr_eq(&road.point_b, &current_stop))
-            }) {
-                total_travel_time += road.travel_time;
+            if let Some(travel_time) = road_travel_time(roads, &current_stop, city) {
+                total_travel_time += travel_time;
 
                 // Check if we have reached the requested stop
                 if Arc::ptr_eq(city, &stop) {
@@ -95,6 +181,58 @@ impl Bus {
         total_travel_time
     }
 
+    /// Returns when the bus reaches the given city on the rest of its route, `None` if it does not go there anymore.
+    pub fn arrival_time(&self, roads: &HashSet<Arc<Road>>, city: &Arc<City>) -> Option<u32> {
+        if !self.route.lock().unwrap().iter().any(|stop| Arc::ptr_eq(stop, city)) {
+            return None;
+        }
+        let stops_passed = *self.stops_passed.lock().unwrap();
+
+        // Follow the route from its very beginning, a looping or reversing bus may already be several rounds in
+        let mut arrival_time = self.departure_time;
+        let mut index = 0;
+        loop {
+            let stop = self.nth_stop(index)?;
+            if index >= stops_passed && Arc::ptr_eq(stop, city) {
+                return Some(arrival_time);
+            }
+            arrival_time += road_travel_time(roads, stop, self.nth_stop(index + 1)?)?;
+            index += 1;
+        }
+    }
+
+    /// Passes the stops the bus drove through before the given time, there is no event for stops where nobody
+    /// got on or off, so the route would still list them as upcoming.
+    fn catch_up(&self, roads: &HashSet<Arc<Road>>, time: u32) {
+        while self.scheduled_arrival(roads, *self.stops_passed.lock().unwrap()).is_some_and(|arrival_time| arrival_time < time) {
+            self.move_to_next();
+        }
+    }
+
+    // When the bus reaches the stop it stands at after passing `index` stops since its departure
+    fn scheduled_arrival(&self, roads: &HashSet<Arc<Road>>, index: usize) -> Option<u32> {
+        let mut arrival_time = self.departure_time;
+        for passed in 0..index {
+            arrival_time += road_travel_time(roads, self.nth_stop(passed)?, self.nth_stop(passed + 1)?)?;
+        }
+        self.nth_stop(index)?;
+        Some(arrival_time)
+    }
+
+    /// The city the bus stands at after passing `index` stops since its departure.
+    fn nth_stop(&self, index: usize) -> Option<&Arc<City>> {
+        let stop_count = self.total_route.len();
+        if self.looping {
+            self.total_route.get(index % stop_count)
+        } else if self.reversing {
+            // One round trip goes there and back without repeating the terminus
+            let position = index % (2 * stop_count - 2);
+            self.total_route.get(position.min(2 * stop_count - 2 - position))
+        } else {
+            self.total_route.get(index)
+        }
+    }
+
 }
 
 #[derive(Clone)]
@@ -106,6 +244,10 @@ pub struct Event {
 }
 
 impl Event {
+    pub fn bus_id(&self) -> u32 {
+        self.bus.get_id()
+    }
+
     pub fn got_off(&self) -> u32 {
         self.got_off_count
     }
@@ -119,15 +261,47 @@ impl Event {
     }
 }
 
+/// Summary of how the network performed so far.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stats {
+    pub total_boarded: u32,
+    pub total_delivered: u32,
+    pub people_still_waiting: u32,
+    pub total_abandoned: u32,
+}
+
+type EventObserver = Box<dyn FnMut(&Event)>;
+
+// People who started waiting for the same connection at the same time
+struct PassengerGroup {
+    count: u32,
+    waiting_since: u32,
+    patience: u32, // how long they wait before giving up
+}
+
 pub struct Simulation {
     buses: Vec<Arc<Bus>>,
     roads: HashSet<Arc<Road>>,
+    cities: HashSet<Arc<City>>,
     // Maps each city to a record of destinations and the number of people waiting to travel there.
     // For each city (key), it holds a map of destination cities (inner key) and passenger counts (value).
     waiting_people: HashMap<Arc<City>, HashMap<Arc<City>, u32>>,
+    // The same people split into groups by when they came, the ones waiting the longest board first
+    passenger_groups: HashMap<(Arc<City>, Arc<City>), VecDeque<PassengerGroup>>,
     next_bus_id: u32,
     event_queue: BTreeMap<u32, BTreeMap<u32, Arc<Event>>>,
     current_time: u32,
+    total_boarded: u32,
+    total_delivered: u32,
+    total_wait_time: u64, // summed over everybody who has boarded
+    total_abandoned: u32,
+    event_observer: Option<EventObserver>,
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Simulation {
@@ -135,20 +309,42 @@ impl Simulation {
         Simulation {
             buses: Vec::new(),
             roads: HashSet::new(),
+            cities: HashSet::new(),
             waiting_people: HashMap::new(),
+            passenger_groups: HashMap::new(),
             next_bus_id: 0,
             event_queue: BTreeMap::new(),
             current_time: 0,
+            total_boarded: 0,
+            total_delivered: 0,
+            total_wait_time: 0,
+            total_abandoned: 0,
+            event_observer: None,
         }
     }
 
+    /// Adds a city, a name that is already taken returns the city added first, as cities are keyed by their names.
     pub fn new_city(&mut self, name: &str) -> Arc<City> {
-        Arc::new(City {
+        let city = Arc::new(City {
             name: name.to_string()
-        })
+        });
+        if let Some(existing) = self.cities.get(&city) {
+            return existing.clone();
+        }
+        self.cities.insert(city.clone());
+        city
     }
 
+    /// Connects two cities, a pair that is already connected keeps a single road with the faster travel time.
+    /// Every road takes at least one time unit, so a bus never stands at two stops at once.
     pub fn new_road(&mut self, a: &Arc<City>, b: &Arc<City>, travel_time: u32) -> Arc<Road> {
+        let travel_time = travel_time.max(1);
+        if let Some(existing) = self.roads.iter().filter(|road| road.connects(a, b)).min_by_key(|road| road.travel_time).cloned() {
+            if existing.travel_time <= travel_time {
+                return existing;
+            }
+            self.roads.remove(&existing);
+        }
         let road = Arc::new(Road {
             travel_time,
             point_a: a.clone(),
@@ -158,32 +354,143 @@ impl Simulation {
         road
     }
 
-    fn valid_route(&self, route: &Vec<Arc<City>>) {
+    // Lists the cities directly connected to the given one together with the travel times
+    fn neighbors(&self, city: &Arc<City>) -> Vec<(Arc<City>, u32)> {
+        self.roads.iter().filter_map(|road| {
+            if Arc::ptr_eq(&road.point_a, city) {
+                Some((road.point_b.clone(), road.travel_time))
+            } else if Arc::ptr_eq(&road.point_b, city) {
+                Some((road.point_a.clone(), road.travel_time))
+            } else {
+                None
+            }
+        }).collect()
+    }
+
+    /// Maps every city on a road to its neighbors and the travel times, each road is listed at both of its ends.
+    pub fn road_graph(&self) -> HashMap<Arc<City>, Vec<(Arc<City>, u32)>> {
+        let mut graph: HashMap<Arc<City>, Vec<(Arc<City>, u32)>> = HashMap::new();
+        for road in &self.roads {
+            graph.entry(road.point_a.clone()).or_default().push((road.point_b.clone(), road.travel_time));
+            graph.entry(road.point_b.clone()).or_default().push((road.point_a.clone(), road.travel_time));
+        }
+        graph
+    }
+
+    /// Finds the fastest way between two cities, returning the total travel time and the visited cities.
+    pub fn shortest_path(&self, from: &Arc<City>, to: &Arc<City>) -> Option<(u32, Vec<Arc<City>>)> {
+        let mut distances: HashMap<Arc<City>, u32> = HashMap::from([(from.clone(), 0)]);
+        let mut previous: HashMap<Arc<City>, Arc<City>> = HashMap::new();
+        let mut queue = BinaryHeap::from([Reverse((0, from.clone()))]);
+
+        while let Some(Reverse((distance, city))) = queue.pop() {
+            if Arc::ptr_eq(&city, to) {
+                let mut path = vec![city];
+                while let Some(city) = previous.get(path.last().unwrap()) {
+                    path.push(city.clone());
+                }
+                path.reverse();
+                return Some((distance, path));
+            }
+            if distance > distances[&city] {
+                continue; // A shorter way to this city was already processed
+            }
+            for (neighbor, travel_time) in self.neighbors(&city) {
+                let new_distance = distance + travel_time;
+                if distances.get(&neighbor).is_none_or(|&known| new_distance < known) {
+                    distances.insert(neighbor.clone(), new_distance);
+                    previous.insert(neighbor.clone(), city.clone());
+                    queue.push(Reverse((new_distance, neighbor)));
+                }
+            }
+        }
+        None
+    }
+
+    /// Checks that every city on a road can be reached from every other one.
+    pub fn is_connected(&self) -> bool {
+        let cities: HashSet<Arc<City>> = self.roads.iter()
+            .flat_map(|road| [road.point_a.clone(), road.point_b.clone()])
+            .collect();
+        let Some(start) = cities.iter().next() else {
+            return true;
+        };
+
+        let mut reached = HashSet::from([start.clone()]);
+        let mut queue = VecDeque::from([start.clone()]);
+        while let Some(city) = queue.pop_front() {
+            for (neighbor, _) in self.neighbors(&city) {
+                if reached.insert(neighbor.clone()) {
+                    queue.push_back(neighbor);
+                }
+            }
+        }
+        reached.len() == cities.len()
+    }
+
+    fn valid_route(&self, route: &[Arc<City>], looping: bool) -> Result<(), String> {
         if route.len() < 2 {
-            panic!("Invalid bus route: A bus must have at least two stops.");
+            return Err("Invalid bus route: A bus must have at least two stops.".to_string());
         }
 
-        let has_valid_roads = route.windows(2).all(|cities| {
-            self.roads.iter().any(|road| 
-                (Arc::ptr_eq(&road.point_a, &cities[0]) && Arc::ptr_eq(&road.point_b, &cities[1])) ||
-                (Arc::ptr_eq(&road.point_a, &cities[1]) && Arc::ptr_eq(&road.point_b, &cities[0]))
-            )
+        // A looping bus also drives from the last stop back to the first one
+        let closing_leg = [route[route.len() - 1].clone(), route[0].clone()];
+        let mut legs: Vec<&[Arc<City>]> = route.windows(2).collect();
+        if looping {
+            legs.push(&closing_leg);
+        }
+        let missing_road = legs.into_iter().find(|cities| {
+            !self.roads.iter().any(|road| road.connects(&cities[0], &cities[1]))
         });
 
-        if !has_valid_roads {
-            panic!("Invalid bus route: Not all consecutive stops in the route have existing roads between them.");
+        if let Some(cities) = missing_road {
+            return Err(format!("Invalid bus route: There is no road between {} and {}.", cities[0].name(), cities[1].name()));
         }
+        Ok(())
     }
 
     fn add_event(&mut self, event: Arc<Event>, time: u32) {
         let bus_id = event.bus.get_id();
-        self.event_queue.entry(time).or_insert_with(BTreeMap::new).insert(bus_id, event);
+        self.event_queue.entry(time).or_default().insert(bus_id, event);
+    }
+
+    /// Adds a bus with unlimited capacity and returns its id, or describes why the route is invalid.
+    pub fn new_bus(&mut self, route: &[&Arc<City>]) -> Result<u32, String> {
+        self.new_bus_with_capacity(route, u32::MAX)
     }
 
-    pub fn new_bus(&mut self, route: &[&Arc<City>]) {
-        let route = route.iter().map(|&city| city.clone()).collect();
-        self.valid_route(&route);
-        let bus = Arc::new(Bus::new(route, self.next_bus_id));
+    pub fn new_bus_with_capacity(&mut self, route: &[&Arc<City>], capacity: u32) -> Result<u32, String> {
+        self.add_bus(route, capacity, false, false, None)
+    }
+
+    /// Adds a bus that keeps cycling its route, returning from the last stop to the first one.
+    /// It stops at every city of the route, even when nobody is waiting there.
+    pub fn new_looping_bus(&mut self, route: &[&Arc<City>]) -> Result<u32, String> {
+        self.add_bus(route, u32::MAX, true, false, None)
+    }
+
+    /// Adds a shuttle bus that turns around at either end of its route and drives it back.
+    pub fn new_reversing_bus(&mut self, route: &[&Arc<City>]) -> Result<u32, String> {
+        self.add_bus(route, u32::MAX, false, true, None)
+    }
+
+    /// Adds a bus known under the given name, e.g. "Line 5".
+    pub fn new_named_bus(&mut self, name: &str, route: &[&Arc<City>]) -> Result<u32, String> {
+        self.add_bus(route, u32::MAX, false, false, Some(name.to_string()))
+    }
+
+    /// Finds the bus with the given name, the first added one if several share it.
+    pub fn bus_by_name(&self, name: &str) -> Option<Arc<Bus>> {
+        self.buses.iter().find(|bus| bus.name() == Some(name)).cloned()
+    }
+
+    fn add_bus(&mut self, route: &[&Arc<City>], capacity: u32, looping: bool, reversing: bool, name: Option<String>) -> Result<u32, String> {
+        let route: Vec<Arc<City>> = route.iter().map(|&city| city.clone()).collect();
+        self.valid_route(&route, looping)?;
+        let bus = Arc::new(Bus {
+            name,
+            ..Bus::new(route, self.next_bus_id, capacity, self.current_time, looping, reversing)
+        });
         self.buses.push(bus.clone());
         self.next_bus_id += 1;
         let first_event = Event {
@@ -193,67 +500,275 @@ impl Simulation {
             got_on_count: 0,
         };
         self.add_event(Arc::new(first_event), self.current_time);
+        Ok(bus.get_id())
+    }
+
+    /// Starts the simulation over, keeping the cities, roads and buses but no waiting people.
+    pub fn reset(&mut self) {
+        self.event_queue.clear();
+        self.waiting_people.clear();
+        self.passenger_groups.clear();
+        self.current_time = 0;
+        self.total_wait_time = 0;
+        self.total_abandoned = 0;
+        self.total_boarded = 0;
+        self.total_delivered = 0;
+        for bus in self.buses.clone() {
+            bus.reset();
+            let first_event = Event {
+                bus: bus.clone(),
+                city: bus.current_stop(),
+                got_off_count: 0,
+                got_on_count: 0,
+            };
+            self.add_event(Arc::new(first_event), bus.departure_time);
+        }
+    }
+
+    /// Returns when the given bus reaches the city, `None` for unknown buses or cities it no longer visits.
+    pub fn arrival_time(&self, bus_id: u32, city: &Arc<City>) -> Option<u32> {
+        let bus = self.buses.iter().find(|bus| bus.get_id() == bus_id)?;
+        bus.arrival_time(&self.roads, city)
+    }
+
+    /// Lists the stops the given bus has yet to reach at the current time, starting with the one it stands at
+    /// or drives to, `None` for unknown buses.
+    pub fn remaining_route(&self, bus_id: u32) -> Option<Vec<Arc<City>>> {
+        let bus = self.buses.iter().find(|bus| bus.get_id() == bus_id)?;
+        let route = bus.route.lock().unwrap().iter().cloned().collect();
+        Some(route)
+    }
+
+    /// Counts the passengers aboard the given bus right now, an unknown bus carries nobody.
+    pub fn occupancy(&self, bus_id: u32) -> u32 {
+        self.buses.iter().find(|bus| bus.get_id() == bus_id).map_or(0, |bus| bus.occupancy())
+    }
+
+    /// Adds people waiting to travel between two cities of the simulation, or describes which city is unknown.
+    pub fn add_people(&mut self, from: &Arc<City>, to: &Arc<City>, count: u32) -> Result<(), String> {
+        self.add_people_with_patience(from, to, count, u32::MAX)
     }
 
-    pub fn add_people(&mut self, from: &Arc<City>, to: &Arc<City>, count: u32) {
+    /// Adds people who give up and leave once they have waited longer than `patience` time units.
+    pub fn add_people_with_patience(&mut self, from: &Arc<City>, to: &Arc<City>, count: u32, patience: u32) -> Result<(), String> {
+        // Cities are told apart by identity, a city of another simulation with the same name is unknown as well
+        if let Some(unknown) = [from, to].into_iter().find(|&city| !self.cities.iter().any(|known| Arc::ptr_eq(known, city))) {
+            return Err(format!("Invalid passengers: {} is not a city of this simulation.", unknown.name()));
+        }
+
         // Retrieve or insert a new inner hashmap for the 'from' city
-        let destination_counts = self.waiting_people.entry(from.clone()).or_insert_with(HashMap::new);
+        let destination_counts = self.waiting_people.entry(from.clone()).or_default();
 
         // Add the number of people to the count for the destination city
         // If the destination city is not already in the map, it's inserted with the count
         *destination_counts.entry(to.clone()).or_insert(0) += count;
+
+        self.passenger_groups.entry((from.clone(), to.clone())).or_default().push_back(PassengerGroup {
+            count,
+            waiting_since: self.current_time,
+            patience,
+        });
+        Ok(())
     }
 
-    fn process_stop(&mut self, event: Arc<Event>, current_time: u32) -> Arc<Event> {
-        let destinations = self.waiting_people.get(&event.city).cloned();
-        let mut event = Arc::try_unwrap(event).unwrap_or_else(|e| (*e).clone()); // Try to unwrap Arc, or clone the content
+    // Sends home everybody who has been waiting for longer than their patience allows
+    fn abandon_impatient(&mut self, current_time: u32) {
+        for ((from, to), groups) in self.passenger_groups.iter_mut() {
+            let mut abandoned = 0;
+            groups.retain(|group| {
+                let gives_up = current_time - group.waiting_since > group.patience;
+                if gives_up {
+                    abandoned += group.count;
+                }
+                !gives_up
+            });
+            if abandoned > 0 {
+                *self.waiting_people.get_mut(from).unwrap().get_mut(to).unwrap() -= abandoned;
+                self.total_abandoned += abandoned;
+            }
+        }
+    }
 
-        if let Some(destinations) = destinations {
-            for (destination, people_waiting) in destinations.iter() {
-                if *people_waiting > 0 && event.bus.is_upcoming_stop(destination.clone()) {
-                    let travel_time = event.bus.calculate_travel_time(&self.roads, destination.clone(), current_time);
-                    
-                    let mut bus_events = self.event_queue.entry(travel_time).or_insert_with(BTreeMap::new);
-                    let existed_event = bus_events.entry(event.bus.get_id()).or_insert_with(|| 
-                        Arc::new(Event {
-                            bus: event.bus.clone(),
-                            city: destination.clone(),
-                            got_off_count: 0,
-                            got_on_count: 0,
-                        })
-                    );
-
-                    let mut existed_event = Arc::make_mut(existed_event);
-                    existed_event.got_off_count += *people_waiting;
-                    event.got_on_count += *people_waiting;
-                    
-                    // Reset the waiting count to 0
-                    let city_waiting_people = self.waiting_people.get_mut(&event.city).unwrap();
-                    *city_waiting_people.get_mut(destination).unwrap() = 0;
+    // Takes the given number of the longest waiting people off the connection and adds up how long they waited
+    fn record_boarding(&mut self, from: &Arc<City>, to: &Arc<City>, mut boarding: u32, current_time: u32) {
+        let groups = self.passenger_groups.entry((from.clone(), to.clone())).or_default();
+        while boarding > 0 {
+            let Some(group) = groups.front_mut() else {
+                break;
+            };
+            let count = group.count.min(boarding);
+            self.total_wait_time += u64::from(count) * u64::from(current_time - group.waiting_since);
+            group.count -= count;
+            boarding -= count;
+            if group.count == 0 {
+                groups.pop_front();
+            }
+        }
+    }
+
+    /// Average time the people who boarded a bus spent waiting for it, people still waiting are not included.
+    pub fn average_wait_time(&self) -> f64 {
+        if self.total_boarded == 0 {
+            return 0.0;
+        }
+        self.total_wait_time as f64 / f64::from(self.total_boarded)
+    }
+
+    /// Counts everybody waiting at the given city regardless of where they are heading.
+    pub fn waiting_at(&self, city: &Arc<City>) -> u32 {
+        self.waiting_people.get(city).map_or(0, |destinations| destinations.values().sum())
+    }
+
+    /// Counts the people waiting at one city to get to another.
+    pub fn waiting_between(&self, from: &Arc<City>, to: &Arc<City>) -> u32 {
+        self.waiting_people.get(from).and_then(|destinations| destinations.get(to)).copied().unwrap_or(0)
+    }
+
+    // Lists the people waiting at the stop of the event for the further stops of the bus, the nearest stop first
+    fn waiting_along_route(&self, event: &Event) -> Vec<(Arc<City>, u32)> {
+        let Some(waiting) = self.waiting_people.get(&event.city) else {
+            return Vec::new();
+        };
+        let mut destinations: Vec<(Arc<City>, u32)> = Vec::new();
+        for stop in event.bus.route.lock().unwrap().iter().skip(1) {
+            if let Some(&count) = waiting.get(stop) {
+                if !destinations.iter().any(|(destination, _)| Arc::ptr_eq(destination, stop)) {
+                    destinations.push((stop.clone(), count));
                 }
             }
         }
+        destinations
+    }
+
+    fn process_stop(&mut self, event: Arc<Event>, current_time: u32) -> Arc<Event> {
+        // Seats are given out in the order of the stops, so who boards a full bus does not depend on hashing
+        let destinations = self.waiting_along_route(&event);
+        let mut event = Arc::try_unwrap(event).unwrap_or_else(|e| (*e).clone()); // Try to unwrap Arc, or clone the content
+        event.bus.alight(event.got_off_count);
+        self.total_delivered += event.got_off_count;
+
+        for (destination, people_waiting) in destinations.iter() {
+            // Only as many people as there are free seats can get on, the rest keeps waiting
+            let boarding = (*people_waiting).min(event.bus.free_seats());
+            if boarding > 0 && event.bus.is_upcoming_stop(destination.clone()) {
+                let travel_time = event.bus.calculate_travel_time(&self.roads, destination.clone(), current_time);
+                
+                let bus_events = self.event_queue.entry(travel_time).or_default();
+                let existed_event = bus_events.entry(event.bus.get_id()).or_insert_with(|| 
+                    Arc::new(Event {
+                        bus: event.bus.clone(),
+                        city: destination.clone(),
+                        got_off_count: 0,
+                        got_on_count: 0,
+                    })
+                );
+
+                // Every road takes at least one time unit, so the stops of one bus never share a time
+                // and an event already scheduled for this bus and time is the one at the destination
+                debug_assert!(Arc::ptr_eq(&existed_event.city, destination));
+
+                // Scheduled events are referenced only from the queue, so this updates the queued event in place
+                // and every boarding person is counted once at each end of the ride
+                let existed_event = Arc::make_mut(existed_event);
+                existed_event.got_off_count += boarding;
+                event.got_on_count += boarding;
+                event.bus.board(boarding);
+                self.total_boarded += boarding;
+                self.record_boarding(&event.city, destination, boarding, current_time);
+                
+                // Leave only the people who did not fit into the bus waiting
+                let city_waiting_people = self.waiting_people.get_mut(&event.city).unwrap();
+                *city_waiting_people.get_mut(destination).unwrap() -= boarding;
+            }
+        }
 
         Arc::new(event)
     }
 
+    pub fn stats(&self) -> Stats {
+        Stats {
+            total_boarded: self.total_boarded,
+            total_delivered: self.total_delivered,
+            people_still_waiting: self.waiting_people.values().flat_map(|destinations| destinations.values()).sum(),
+            total_abandoned: self.total_abandoned,
+        }
+    }
+
+    // Removes the event of the lowest bus id scheduled for the given time from the queue
+    fn take_next_event(&mut self, time: u32) -> Option<Arc<Event>> {
+        let bus_events = self.event_queue.get_mut(&time)?;
+        let (_, event) = bus_events.pop_first()?;
+        if bus_events.is_empty() {
+            self.event_queue.remove(&time);
+        }
+        Some(event)
+    }
+
+    // Makes the bus of the event stop at the next city of its route, if it does not stop there already
+    fn schedule_next_stop(&mut self, event: &Event, current_time: u32) {
+        let next_stop = event.bus.current_stop();
+        let Some(travel_time) = road_travel_time(&self.roads, &event.city, &next_stop) else {
+            return;
+        };
+        let bus_events = self.event_queue.entry(current_time + travel_time).or_default();
+        bus_events.entry(event.bus.get_id()).or_insert_with(|| Arc::new(Event {
+            bus: event.bus.clone(),
+            city: next_stop,
+            got_off_count: 0,
+            got_on_count: 0,
+        }));
+    }
+
+    /// Returns the time of the earliest scheduled event, `None` if nothing is scheduled anymore.
+    pub fn peek_next_event_time(&self) -> Option<u32> {
+        self.event_queue.keys().next().copied()
+    }
+
+    /// Jumps to the next scheduled event time and processes only that time slot.
+    pub fn step(&mut self) -> Vec<Arc<Event>> {
+        match self.peek_next_event_time() {
+            Some(time) => {
+                self.current_time = self.current_time.max(time);
+                self.execute(1)
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Registers a callback that sees every event right after it is processed, in the order of processing.
+    pub fn set_event_observer(&mut self, f: EventObserver) {
+        self.event_observer = Some(f);
+    }
+
     pub fn execute(&mut self, time_units_count: u32) -> Vec<Arc<Event>> {
         let mut events = Vec::new();
         let end_time = self.current_time + time_units_count; // Calculate end time once
 
         for current_time in self.current_time..end_time {
-            if let Some(bus_events) = self.event_queue.get_mut(&current_time) {
-                let cloned_events: Vec<_> = bus_events.values().cloned().collect(); // Clone the bus events
-                
-                for event in cloned_events {
-                    let processed_event = self.process_stop(event, current_time);
-                    processed_event.bus.move_to_next();
-                    events.push(processed_event);
+            self.abandon_impatient(current_time);
+            // Events of one time slot are processed in ascending bus id order. Events scheduled for
+            // the current time while processing join the slot and are handled within the same step.
+            // Within an event the seats go to the people heading to the nearest stop first, so a rerun
+            // of the same scenario gives the very same events.
+            while let Some(event) = self.take_next_event(current_time) {
+                event.bus.arrive_at(&event.city);
+                let processed_event = self.process_stop(event, current_time);
+                processed_event.bus.move_to_next();
+                // A looping bus stops everywhere even with nobody aboard, so people who come later are picked up
+                if processed_event.bus.looping {
+                    self.schedule_next_stop(&processed_event, current_time);
                 }
+                if let Some(observer) = self.event_observer.as_mut() {
+                    observer(&processed_event);
+                }
+                events.push(processed_event);
             }
         }
 
         self.current_time += time_units_count; // Update the current time of the simulation
+        for bus in &self.buses {
+            bus.catch_up(&self.roads, self.current_time);
+        }
 
         events
     }